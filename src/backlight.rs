@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// A panel backlight under `class/backlight` in sysfs. Writing it needs root, so it's only used
+/// from the gadget process.
+pub struct Backlight {
+    dir: PathBuf,
+    max: u32,
+    // The last brightness written or seen, to tell changes made on the device from our own.
+    last: u32,
+}
+
+impl Backlight {
+    /// Finds the first backlight under the given sysfs root, if the device has one.
+    pub fn find(sysfs_root: &Path) -> anyhow::Result<Option<Self>> {
+        let class_dir = sysfs_root.join("class/backlight");
+        let Some(entry) = std::fs::read_dir(&class_dir)
+            .with_context(|| format!("failed to list {}", class_dir.display()))?
+            .next()
+        else {
+            return Ok(None);
+        };
+        let dir = entry?.path();
+        let max = read_value(&dir.join("max_brightness"))?;
+        let last = read_value(&dir.join("brightness"))?;
+        Ok(Some(Self { dir, max: max.max(1), last }))
+    }
+
+    /// The current brightness as a percentage, if it changed since last seen.
+    pub fn poll(&mut self) -> anyhow::Result<Option<u8>> {
+        let value = read_value(&self.dir.join("brightness"))?;
+        if value == self.last {
+            return Ok(None);
+        }
+        self.last = value;
+        Ok(Some(self.percent()))
+    }
+
+    pub fn percent(&self) -> u8 {
+        (self.last * 100 / self.max) as u8
+    }
+
+    pub fn set_percent(&mut self, percent: u8) -> anyhow::Result<()> {
+        let value = self.max * percent.min(100) as u32 / 100;
+        std::fs::write(self.dir.join("brightness"), value.to_string())
+            .context("failed to set backlight brightness")?;
+        self.last = value;
+        Ok(())
+    }
+}
+
+fn read_value(path: &Path) -> anyhow::Result<u32> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .trim()
+        .parse()
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// A fake sysfs root in the temp dir, holding a backlight at `brightness` out of `max` if given.
+#[cfg(test)]
+pub fn fake_sysfs(name: &str, backlight: Option<(u32, u32)>) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("usb-kvm-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&root);
+    let class_dir = root.join("class/backlight");
+    std::fs::create_dir_all(&class_dir).unwrap();
+    if let Some((brightness, max)) = backlight {
+        let dir = class_dir.join("panel");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("brightness"), format!("{}\n", brightness)).unwrap();
+        std::fs::write(dir.join("max_brightness"), format!("{}\n", max)).unwrap();
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_needs_a_backlight() {
+        let root = fake_sysfs("find", None);
+        assert!(Backlight::find(&root).unwrap().is_none());
+        assert!(Backlight::find(&root.join("missing")).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn percent_is_scaled_to_max() {
+        let root = fake_sysfs("percent", Some((51, 255)));
+        let backlight = Backlight::find(&root).unwrap().unwrap();
+        assert_eq!(backlight.percent(), 20);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn set_percent_writes_brightness() {
+        let root = fake_sysfs("set_percent", Some((0, 255)));
+        let brightness = root.join("class/backlight/panel/brightness");
+        let mut backlight = Backlight::find(&root).unwrap().unwrap();
+
+        backlight.set_percent(40).unwrap();
+        assert_eq!(std::fs::read_to_string(&brightness).unwrap(), "102");
+        // Our own change isn't reported back.
+        assert_eq!(backlight.poll().unwrap(), None);
+
+        backlight.set_percent(200).unwrap();
+        assert_eq!(std::fs::read_to_string(&brightness).unwrap(), "255");
+
+        // Changed on the device.
+        std::fs::write(&brightness, "0\n").unwrap();
+        assert_eq!(backlight.poll().unwrap(), Some(0));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,43 +1,503 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use crate::AppEvent;
-use gud_gadget::{Event, PixelDataEndpoint};
+use crate::{edid, gud, modes, AppEvent};
+use crate::formats::PixelFormat;
+use crate::mouse::TouchButton;
+use crate::renderer::{Rect, Renderer, ViewTransform};
+use crate::gud::{Event, Gud, PixelDataEndpoint};
+use clap::ValueEnum;
 use pixels::{Pixels, SurfaceTexture};
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, trace};
 use usb_gadget::Class;
 use usb_gadget::function::custom::{Custom, Interface};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{Touch, TouchPhase};
 use winit::event_loop::EventLoopProxy;
 use winit::window::Window;
 
-const WIDTH: u32 = 768;
-const HEIGHT: u32 = 1024;
+/// Smallest and largest framebuffer the host may set up.
+const MIN_DIMENSION: u32 = 240;
+const MAX_DIMENSION: u32 = 4096;
+
+/// The virtual trackpad drawn below the mirrored display is this fraction of the display's height.
+const TRACKPAD_HEIGHT_NUM: u32 = 3;
+const TRACKPAD_HEIGHT_DEN: u32 = 8;
+/// The click buttons take up this fraction of the virtual trackpad.
+const TRACKPAD_BUTTON_HEIGHT_DEN: u32 = 4;
+
+// Colours are in the frame's XRGB8888 byte order: blue, green, red, padding.
+const TRACKPAD_COLOR: [u8; 4] = [0x30, 0x30, 0x30, 0xFF];
+const TRACKPAD_BUTTON_COLOR: [u8; 4] = [0x48, 0x48, 0x48, 0xFF];
+const TRACKPAD_EDGE_COLOR: [u8; 4] = [0x80, 0x80, 0x80, 0xFF];
+
+/// Radius of the predicted cursor overlay, in framebuffer pixels.
+const CURSOR_OVERLAY_RADIUS: i32 = 6;
+
+/// Width of the border drawn around the host framebuffer while calibrating, in framebuffer pixels.
+const CALIBRATION_BORDER: u32 = 4;
+const CALIBRATION_COLOR: [u8; 4] = [0x00, 0xA0, 0xFF, 0xFF];
+
+/// Size of the magnifier loupe, in framebuffer pixels.
+const LOUPE_SIZE: i32 = 160;
+const LOUPE_ZOOM: i32 = 3;
+/// How far above the finger the loupe is centred, so the finger doesn't cover it.
+const LOUPE_OFFSET: i32 = 140;
+
+/// How far the view can be zoomed in locally.
+const MAX_ZOOM: f64 = 8.0;
+
+/// Fingers it takes to pan and zoom the view, so fewer are still the host's to scroll and pinch.
+/// Three finger taps and long-presses are the mouse's too, they only count while the view stays
+/// put.
+const VIEW_GESTURE_FINGERS: usize = 3;
+/// How far the fingers' midpoint has to move, or with local zoom how far they have to spread or
+/// pinch, in window pixels, before they take over the view. Until then they're passed on, and once
+/// they do they're cancelled for the host, so a three finger tap or long-press never comes along
+/// with the view moving.
+const VIEW_GESTURE_SLOP: f64 = 24.0;
+
+/// Length of the minimap's longer side and its distance from the edge of the window, in window
+/// pixels.
+const MINIMAP_SIZE: f64 = 120.0;
+const MINIMAP_MARGIN: f64 = 12.0;
+const MINIMAP_VIEWPORT_COLOR: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Layout {
+    /// The mirrored display fills the window and touches anywhere on it control the host.
+    #[default]
+    Direct,
+    /// A virtual trackpad below the mirrored display takes the touches, so the picture isn't
+    /// covered by fingers.
+    Trackpad,
+}
+
+/// How the mirrored display is turned in the window, clockwise. Quarter turns swap the native
+/// mode's width and height, so the host sees a monitor that's the right way up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Serialize, Deserialize)]
+pub enum Rotation {
+    #[default]
+    #[value(name = "0")]
+    R0,
+    #[value(name = "90")]
+    R90,
+    #[value(name = "180")]
+    R180,
+    #[value(name = "270")]
+    R270,
+}
+
+impl Rotation {
+    pub fn next(self) -> Self {
+        match self {
+            Rotation::R0 => Rotation::R90,
+            Rotation::R90 => Rotation::R180,
+            Rotation::R180 => Rotation::R270,
+            Rotation::R270 => Rotation::R0,
+        }
+    }
+
+    pub fn is_quarter_turn(self) -> bool {
+        matches!(self, Rotation::R90 | Rotation::R270)
+    }
+
+    /// Swaps a size's dimensions if turned a quarter.
+    pub fn apply(self, size: PhysicalSize<u32>) -> PhysicalSize<u32> {
+        if self.is_quarter_turn() {
+            PhysicalSize::new(size.height, size.width)
+        } else {
+            size
+        }
+    }
+
+    /// The GUD rotation property turns the other way.
+    fn from_gud(value: u64) -> Option<Self> {
+        match value {
+            gud::GUD_ROTATION_0 => Some(Rotation::R0),
+            gud::GUD_ROTATION_90 => Some(Rotation::R270),
+            gud::GUD_ROTATION_180 => Some(Rotation::R180),
+            gud::GUD_ROTATION_270 => Some(Rotation::R90),
+            _ => None,
+        }
+    }
+
+    /// Where pixel `x`, `y` of a buffer of the given size ends up once the buffer is turned.
+    fn turn_point(self, x: u32, y: u32, size: PhysicalSize<u32>) -> (u32, u32) {
+        match self {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (size.height - 1 - y, x),
+            Rotation::R180 => (size.width - 1 - x, size.height - 1 - y),
+            Rotation::R270 => (y, size.width - 1 - x),
+        }
+    }
+
+    /// Where a non-empty rect of a buffer of the given size ends up once the buffer is turned.
+    fn turn_rect(self, rect: Rect, size: PhysicalSize<u32>) -> Rect {
+        let a = self.turn_point(rect.x, rect.y, size);
+        let b = self.turn_point(rect.x + rect.width - 1, rect.y + rect.height - 1, size);
+        let turned = self.apply(PhysicalSize::new(rect.width, rect.height));
+        Rect { x: a.0.min(b.0), y: a.1.min(b.1), width: turned.width, height: turned.height }
+    }
+}
+
+/// How the mirrored display is scaled into the window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ScaleMode {
+    /// As large as fits, letterboxed.
+    #[default]
+    Fit,
+    /// Covers the whole window, cropping whatever overflows.
+    Fill,
+    /// The largest whole multiple that fits, keeping pixels sharp.
+    Integer,
+    /// One host pixel per window pixel.
+    #[value(name = "1:1")]
+    OneToOne,
+}
+
+impl ScaleMode {
+    pub fn next(self) -> Self {
+        match self {
+            ScaleMode::Fit => ScaleMode::Fill,
+            ScaleMode::Fill => ScaleMode::Integer,
+            ScaleMode::Integer => ScaleMode::OneToOne,
+            ScaleMode::OneToOne => ScaleMode::Fit,
+        }
+    }
+}
+
+/// What to do with a touch after [`Display::handle_pan`] has seen it.
+pub enum Pan {
+    /// Not part of a pan, handle it as usual.
+    Pass,
+    /// Taken for panning the view.
+    Consumed,
+    /// The fingers started panning. Their touches, given here in window coordinates, have to be
+    /// cancelled wherever they were going.
+    Started(Vec<Touch>),
+}
+
+/// Three fingers panning, and with local zoom pinching, the view.
+struct PanGesture {
+    /// Whether the fingers moved far enough to take over from the host.
+    active: bool,
+    // Where the midpoint was and how far the fingers were spread when the last one landed, until
+    // active.
+    start: PhysicalPosition<f64>,
+    start_spread: f64,
+    midpoint: PhysicalPosition<f64>,
+    // Average distance of the fingers from their midpoint.
+    spread: f64,
+}
+
+/// Which part of the window a touch landed in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TouchRegion {
+    Display,
+    Trackpad,
+    /// One of the virtual trackpad's click buttons.
+    TrackpadButton(TouchButton),
+}
+
+/// A framebuffer size, either the one advertised to the host as native or the one it picked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mode {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Mode {
+    /// Physical size of the mode on the local panel, going by the window's scale factor, i.e. its
+    /// DPI relative to 96.
+    fn size_mm(&self, scale_factor: f64) -> (u32, u32) {
+        let mm = |pixels: u32| (pixels as f64 / (96.0 * scale_factor) * 25.4).round() as u32;
+        (mm(self.width), mm(self.height))
+    }
+
+    /// Works out the mode that fills a window, leaving room for the virtual trackpad if needed.
+    fn for_window(size: PhysicalSize<u32>, layout: Layout, rotation: Rotation) -> Self {
+        let size = rotation.apply(size);
+        let height = match layout {
+            Layout::Direct => size.height,
+            Layout::Trackpad => size.height * TRACKPAD_HEIGHT_DEN / (TRACKPAD_HEIGHT_DEN + TRACKPAD_HEIGHT_NUM),
+        };
+        // Hosts are happier with widths that are a multiple of 8.
+        Self {
+            width: (size.width & !7).clamp(MIN_DIMENSION, MAX_DIMENSION),
+            height: height.clamp(MIN_DIMENSION, MAX_DIMENSION),
+        }
+    }
+}
+
+/// The pixel buffer, shared between the UI and the thread receiving frames from the host.
+struct Framebuffer {
+    // Only used for the surface, the frame lives in `frame` and is uploaded by the renderer.
+    pixels: Pixels,
+    renderer: Renderer,
+    /// XRGB8888 pixels of the host framebuffer, followed by the virtual trackpad if there is one.
+    frame: Vec<u8>,
+    /// The part of `frame` that changed since it was last uploaded.
+    damage: Option<Rect>,
+    /// The mode the host is currently sending frames for.
+    mode: Mode,
+    /// The preferred mode advertised to the host, which follows the window size.
+    native: Mode,
+    /// The window's scale factor, which the physical size in the EDID is worked out from.
+    scale_factor: f64,
+    /// The format the host is sending pixels in.
+    format: PixelFormat,
+    // Incoming pixel data before it's converted into the frame.
+    staging: Vec<u8>,
+    // LZ4 compressed transfers are received here and decompressed into `staging`.
+    compressed: Vec<u8>,
+    // Converted pixels waiting to be turned into the frame, see `host_rotation`.
+    turned: Vec<u8>,
+    /// How the host asked for its buffers to be turned, through the GUD rotation property. The
+    /// frame is kept the way up the mode is, so only incoming buffers need turning.
+    host_rotation: Rotation,
+    /// Panel backlight brightness as a percentage, if the device has a backlight.
+    backlight: Option<u8>,
+    /// The brightness the host last read or committed. It can't be told about changes made on the
+    /// device, so only a commit that differs from this is the host actually setting it.
+    host_backlight: Option<u8>,
+    /// Whether the virtual monitor is plugged in, as reported to the host.
+    connected: bool,
+    // Set when the native mode or connector status changed and the host has yet to be told.
+    hotplug: bool,
+    surface_size: PhysicalSize<u32>,
+    rotation: Rotation,
+    scale_mode: ScaleMode,
+    // Local magnification on top of the scale mode.
+    zoom: f64,
+    // Offset from centred in window pixels, along the axes the buffer overflows the window.
+    pan: (f64, f64),
+    layout: Layout,
+    trackpad_buttons: usize,
+}
+
+impl Framebuffer {
+    fn buffer_size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.mode.width, buffer_height(self.mode, self.layout))
+    }
+
+    fn damage(&mut self, rect: Rect) {
+        self.damage = Some(match self.damage {
+            Some(damage) => damage.union(rect),
+            None => rect,
+        });
+    }
+
+    fn damage_all(&mut self) {
+        let size = self.buffer_size();
+        self.damage = Some(Rect { x: 0, y: 0, width: size.width, height: size.height });
+    }
+
+    fn transform(&self) -> ViewTransform {
+        ViewTransform::new(self.buffer_size(), self.surface_size, self.rotation, self.scale_mode, self.zoom, self.pan)
+    }
+
+    fn update_transform(&mut self) {
+        let transform = self.transform();
+        // Don't let the pan build up past the edges.
+        self.pan = transform.pan(self.surface_size);
+        self.renderer.set_transform(&self.pixels, transform, self.surface_size);
+    }
+
+    /// Multiplies the local zoom, keeping the buffer pixel under the window position `at` in place.
+    fn zoom_at(&mut self, at: PhysicalPosition<f64>, factor: f64) {
+        let before = self.transform();
+        self.zoom = (self.zoom * factor).clamp(1.0, MAX_ZOOM);
+        let after = self.transform();
+        // Window position = offset + buffer position * scale, so holding `at` still moves the
+        // offset towards it in proportion to the change in scale.
+        let factor = after.scale / before.scale;
+        let target = (at.x - (at.x - before.offset.0) * factor, at.y - (at.y - before.offset.1) * factor);
+        self.pan = (self.pan.0 + target.0 - after.offset.0, self.pan.1 + target.1 - after.offset.1);
+        self.update_transform();
+    }
+
+    /// Switches the pixel buffer to the mode the host picked.
+    fn set_mode(&mut self, mode: Mode) {
+        if mode == self.mode {
+            return;
+        }
+        info!("switching to mode {}x{}", mode.width, mode.height);
+        self.mode = mode;
+        let buffer_size = self.buffer_size();
+        self.frame = vec![0; (buffer_size.width * buffer_size.height * 4) as usize];
+        self.renderer = Renderer::new(&self.pixels, buffer_size);
+        if self.layout == Layout::Trackpad {
+            draw_trackpad(&mut self.frame, mode, self.trackpad_buttons);
+        }
+        self.damage_all();
+        self.update_transform();
+    }
+}
+
+/// Height of the pixel buffer, including the virtual trackpad below the host framebuffer.
+fn buffer_height(mode: Mode, layout: Layout) -> u32 {
+    match layout {
+        Layout::Direct => mode.height,
+        Layout::Trackpad => mode.height + trackpad_height(mode.height),
+    }
+}
+
+fn trackpad_height(height: u32) -> u32 {
+    height * TRACKPAD_HEIGHT_NUM / TRACKPAD_HEIGHT_DEN
+}
 
 pub struct Display {
-    pixels: Arc<Mutex<Pixels>>,
+    fb: Arc<Mutex<Framebuffer>>,
     events: EventLoopProxy<AppEvent>,
+    layout: Layout,
+    trackpad_buttons: usize,
+    // Whether three fingers always zoom and pan the view, rather than only when it overflows.
+    local_zoom: bool,
+    // Touches in window coordinates, tracked for panning the view.
+    pan_touches: HashMap<u64, PhysicalPosition<f64>>,
+    pan_gesture: Option<PanGesture>,
+    cursor_overlay: Option<PhysicalPosition<f64>>,
+    loupe: Option<PhysicalPosition<f64>>,
+    calibration_target: Option<PhysicalPosition<f64>>,
 }
 
 impl Display {
     pub fn new(
         events: EventLoopProxy<AppEvent>,
         window: &Window,
+        layout: Layout,
+        trackpad_buttons: usize,
+        rotation: Rotation,
+        scale_mode: ScaleMode,
+        local_zoom: bool,
     ) -> Self {
+        let surface_size = window.inner_size();
+        let mode = Mode::for_window(surface_size, layout, rotation);
+
+        // The virtual trackpad lives in the same pixel buffer, below the host framebuffer, so it's
+        // scaled along with the mirrored display.
+        let buffer_size = PhysicalSize::new(mode.width, buffer_height(mode, layout));
         let pixels = {
-            let window_size = window.inner_size();
             let surface_texture =
-                SurfaceTexture::new(window_size.width, window_size.height, &window);
-            Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
+                SurfaceTexture::new(surface_size.width, surface_size.height, &window);
+            Pixels::new(1, 1, surface_texture).unwrap()
         };
-
-        let pixels = Arc::new(Mutex::new(pixels));
+        let renderer = Renderer::new(&pixels, buffer_size);
+        let mut fb = Framebuffer {
+            pixels,
+            renderer,
+            frame: vec![0; (buffer_size.width * buffer_size.height * 4) as usize],
+            damage: None,
+            mode,
+            native: mode,
+            scale_factor: window.scale_factor(),
+            format: PixelFormat::Xrgb8888,
+            staging: Vec::new(),
+            compressed: Vec::new(),
+            turned: Vec::new(),
+            host_rotation: Rotation::R0,
+            backlight: None,
+            host_backlight: None,
+            // Plugged in once the window is up, see Display::set_connected.
+            connected: false,
+            hotplug: false,
+            surface_size,
+            rotation,
+            scale_mode,
+            zoom: 1.0,
+            pan: (0.0, 0.0),
+            layout,
+            trackpad_buttons,
+        };
+        if layout == Layout::Trackpad {
+            draw_trackpad(&mut fb.frame, mode, trackpad_buttons);
+        }
+        fb.damage_all();
+        fb.update_transform();
 
         Self {
-            pixels,
+            fb: Arc::new(Mutex::new(fb)),
             events,
+            layout,
+            trackpad_buttons,
+            local_zoom,
+            pan_touches: HashMap::new(),
+            pan_gesture: None,
+            cursor_overlay: None,
+            loupe: None,
+            calibration_target: None,
         }
     }
 
+    /// The size of the host framebuffer, excluding any touch controls drawn below it.
+    pub fn framebuffer_size(&self) -> PhysicalSize<u32> {
+        let mode = self.fb.lock().unwrap().mode;
+        PhysicalSize::new(mode.width, mode.height)
+    }
+
+    /// Maps a window position into pixel buffer coordinates, undoing the scaling and letterboxing
+    /// done when rendering. Positions in the letterbox bars are clamped to the buffer's edge and
+    /// returned as an error.
+    pub fn window_to_buffer(&self, pos: PhysicalPosition<f64>) -> Result<PhysicalPosition<f64>, PhysicalPosition<f64>> {
+        let (transform, buffer_size) = {
+            let fb = self.fb.lock().unwrap();
+            (fb.transform(), fb.buffer_size())
+        };
+        let (buffer_width, buffer_height) = (buffer_size.width as f64, buffer_size.height as f64);
+        let PhysicalPosition { x, y } = transform.window_to_buffer(pos);
+
+        if (0.0..buffer_width).contains(&x) && (0.0..buffer_height).contains(&y) {
+            Ok(PhysicalPosition::new(x, y))
+        } else {
+            Err(PhysicalPosition::new(
+                x.clamp(0.0, buffer_width - 1.0),
+                y.clamp(0.0, buffer_height - 1.0),
+            ))
+        }
+    }
+
+    /// Works out which part of the layout a pixel buffer position falls in.
+    pub fn region_at(&self, pos: PhysicalPosition<f64>) -> TouchRegion {
+        if self.layout == Layout::Direct {
+            return TouchRegion::Display;
+        }
+        let mode = self.fb.lock().unwrap().mode;
+        let trackpad_height = trackpad_height(mode.height);
+        let (x, y) = (pos.x as u32, pos.y as u32);
+        if y < mode.height {
+            TouchRegion::Display
+        } else if self.trackpad_buttons > 0
+            && y >= mode.height + trackpad_height - trackpad_height / TRACKPAD_BUTTON_HEIGHT_DEN
+        {
+            let buttons = match self.trackpad_buttons {
+                1 => &[TouchButton::Left][..],
+                2 => &[TouchButton::Left, TouchButton::Right],
+                _ => &[TouchButton::Left, TouchButton::Middle, TouchButton::Right],
+            };
+            TouchRegion::TrackpadButton(buttons[(x as usize * buttons.len() / mode.width as usize).min(buttons.len() - 1)])
+        } else {
+            TouchRegion::Trackpad
+        }
+    }
+
+    /// Maps a pixel buffer position to the host framebuffer position it points at. The virtual
+    /// trackpad, above its click buttons, stands for the whole framebuffer like a graphics tablet.
+    pub fn absolute_position(&self, pos: PhysicalPosition<f64>) -> PhysicalPosition<f64> {
+        let mode = self.fb.lock().unwrap().mode;
+        let height = mode.height as f64;
+        if self.layout == Layout::Direct || pos.y < height {
+            return pos;
+        }
+        let trackpad_height = trackpad_height(mode.height);
+        let surface_height = match self.trackpad_buttons {
+            0 => trackpad_height,
+            _ => trackpad_height - trackpad_height / TRACKPAD_BUTTON_HEIGHT_DEN,
+        } as f64;
+        PhysicalPosition::new(pos.x, ((pos.y - height) / surface_height * height).min(height - 1.0))
+    }
+
     pub fn setup(&mut self, ffs_dir: String) -> anyhow::Result<()> {
         let (gud_data, gud_data_ep) = PixelDataEndpoint::new();
         let gud_func = Custom::builder()
@@ -47,59 +507,567 @@ impl Display {
             .existing(ffs_dir)?;
 
         {
-            let pixels = self.pixels.clone();
+            let fb = self.fb.clone();
             let events = self.events.clone();
             std::thread::spawn(move || {
-                run(gud_func, gud_data, pixels, events);
+                run(gud_func, gud_data, fb, events);
             });
         }
 
         Ok(())
     }
 
+    /// Shows a small marker at the given framebuffer position, where the host pointer is predicted
+    /// to be before the frame showing it has arrived.
+    pub fn set_cursor_overlay(&mut self, pos: Option<PhysicalPosition<f64>>) {
+        self.cursor_overlay = pos;
+    }
+
+    /// Shows a magnified view of the framebuffer around the given position, above the finger
+    /// touching it.
+    pub fn set_loupe(&mut self, pos: Option<PhysicalPosition<f64>>) {
+        self.loupe = pos;
+    }
+
+    /// Outlines the host framebuffer and marks where the host pointer is expected to be, while the
+    /// calibration wizard waits for a tap on the actual pointer.
+    pub fn set_calibration_target(&mut self, pos: Option<PhysicalPosition<f64>>) {
+        self.calibration_target = pos;
+    }
+
     pub fn render(&mut self) {
-        if let Err(err) = self.pixels.lock().unwrap().render() {
+        let mut fb = self.fb.lock().unwrap();
+
+        // Overlays are drawn straight into the frame and the covered pixels put back after
+        // rendering, since partial host updates only resend what changed on the host.
+        let mut saved = vec![];
+        let mode = fb.mode;
+        if let Some(pos) = self.loupe {
+            draw_loupe(&mut fb.frame, mode, &mut saved, pos);
+        }
+        if let Some(pos) = self.cursor_overlay {
+            draw_cursor_overlay(&mut fb.frame, mode, &mut saved, pos);
+        }
+        if let Some(pos) = self.calibration_target {
+            draw_calibration_prompt(&mut fb.frame, mode, &mut saved, pos);
+        }
+        let overlay = saved_bounds(&saved, mode);
+        if let Some(rect) = overlay {
+            fb.damage(rect);
+        }
+
+        // The minimap is drawn over the view in window pixels instead, so it isn't turned with it.
+        let (transform, surface_size) = (fb.transform(), fb.surface_size);
+        let fills = if transform.overflows(surface_size) { minimap(transform, surface_size) } else { vec![] };
+
+        let Framebuffer { pixels, renderer, frame, damage, .. } = &mut *fb;
+        if let Some(rect) = damage.take() {
+            renderer.upload(pixels, frame, rect);
+        }
+        renderer.set_fills(pixels, &fills);
+        let result = pixels.render_with(|encoder, render_target, _| {
+            renderer.render(encoder, render_target);
+            Ok(())
+        });
+        if let Err(err) = result {
             // TODO: properly handle this
             error!("pixels.render {}", err);
         }
+
+        // Restore in reverse, in case overlays overlapped. The restored pixels need uploading
+        // again next time.
+        for (offset, color) in saved.into_iter().rev() {
+            frame[offset..offset + 4].copy_from_slice(&color);
+        }
+        if let Some(rect) = overlay {
+            fb.damage(rect);
+        }
     }
 
-    pub fn resize(&mut self, width: u32, height: u32) {
-        if let Err(err) = self.pixels.lock().unwrap().resize_surface(width, height) {
+    /// Resizes the surface to a new window size. The native mode follows the window, so the host
+    /// is told to re-read the display modes when it changes.
+    pub fn resize(&mut self, size: PhysicalSize<u32>, scale_factor: f64) {
+        let mut fb = self.fb.lock().unwrap();
+        if let Err(err) = fb.pixels.resize_surface(size.width, size.height) {
             error!("pixels.resize_surface {}", err);
         }
+        fb.surface_size = size;
+        fb.update_transform();
+        self.update_native(&mut fb, scale_factor);
+    }
+
+    /// Plugs the virtual monitor in or out. The gadget stays bound either way, so the keyboard
+    /// and pointing devices keep working.
+    pub fn set_connected(&mut self, connected: bool) {
+        let mut fb = self.fb.lock().unwrap();
+        if fb.connected != connected {
+            info!("virtual monitor {}", if connected { "plugged in" } else { "unplugged" });
+            fb.connected = connected;
+            fb.hotplug = true;
+        }
+    }
+
+    /// Updates the backlight brightness reported to the host, after it was changed on the device.
+    ///
+    /// GUD has no way to tell the host a property changed, and hosts only read the connector
+    /// properties when probing the device, so the host's brightness setting isn't updated to
+    /// match. States it commits in the meantime still carry its old brightness, which is ignored
+    /// until the host changes it.
+    pub fn set_backlight(&mut self, percent: u8) {
+        self.fb.lock().unwrap().backlight = Some(percent);
+    }
+
+    pub fn scale_mode(&self) -> ScaleMode {
+        self.fb.lock().unwrap().scale_mode
+    }
+
+    pub fn set_scale_mode(&mut self, scale_mode: ScaleMode) {
+        let mut fb = self.fb.lock().unwrap();
+        info!("scaling display with {:?}", scale_mode);
+        fb.scale_mode = scale_mode;
+        fb.zoom = 1.0;
+        fb.pan = (0.0, 0.0);
+        fb.update_transform();
+    }
+
+    /// Pans the view with a three finger drag when the mirrored display overflows the window, and
+    /// with local zoom enabled pinches zoom it. Pinching out past the whole display re-centres it.
+    /// Fewer fingers are left to the host, and only the direct layout pans.
+    pub fn handle_pan(&mut self, touch: Touch) -> Pan {
+        match touch.phase {
+            TouchPhase::Started | TouchPhase::Moved => self.pan_touches.insert(touch.id, touch.location),
+            TouchPhase::Ended | TouchPhase::Cancelled => self.pan_touches.remove(&touch.id),
+        };
+        let geometry = pan_geometry(&self.pan_touches);
+
+        match &mut self.pan_gesture {
+            Some(gesture) if gesture.active => {
+                let Some((midpoint, spread)) = geometry else {
+                    self.pan_gesture = None;
+                    return Pan::Consumed;
+                };
+                // Fingers landing or lifting move the midpoint without the view following it.
+                if touch.phase == TouchPhase::Moved {
+                    let delta = (midpoint.x - gesture.midpoint.x, midpoint.y - gesture.midpoint.y);
+                    let factor = match self.local_zoom && gesture.spread > 0.0 {
+                        true => spread / gesture.spread,
+                        false => 1.0,
+                    };
+                    let mut fb = self.fb.lock().unwrap();
+                    fb.pan = (fb.pan.0 + delta.0, fb.pan.1 + delta.1);
+                    fb.zoom_at(midpoint, factor);
+                    if factor < 1.0 && fb.zoom <= 1.0 {
+                        fb.pan = (0.0, 0.0);
+                        fb.update_transform();
+                    }
+                }
+                gesture.midpoint = midpoint;
+                gesture.spread = spread;
+                Pan::Consumed
+            }
+            Some(gesture) => {
+                let Some((midpoint, spread)) = geometry else {
+                    self.pan_gesture = None;
+                    return Pan::Pass;
+                };
+                if touch.phase != TouchPhase::Moved || self.pan_touches.len() != VIEW_GESTURE_FINGERS {
+                    self.pan_gesture = None;
+                    return Pan::Pass;
+                }
+                let moved = (midpoint.x - gesture.start.x).hypot(midpoint.y - gesture.start.y);
+                // A pinch can keep its midpoint in place, it has to take over the same way.
+                let pinched = match self.local_zoom {
+                    true => (spread - gesture.start_spread).abs(),
+                    false => 0.0,
+                };
+                if moved < VIEW_GESTURE_SLOP && pinched < VIEW_GESTURE_SLOP {
+                    return Pan::Pass;
+                }
+                *gesture = PanGesture { active: true, midpoint, spread, ..*gesture };
+                Pan::Started(
+                    self.pan_touches
+                        .iter()
+                        .map(|(&id, &location)| Touch { id, location, phase: TouchPhase::Cancelled, ..touch })
+                        .collect(),
+                )
+            }
+            None => {
+                if touch.phase != TouchPhase::Started
+                    || self.pan_touches.len() != VIEW_GESTURE_FINGERS
+                    || self.layout != Layout::Direct
+                {
+                    return Pan::Pass;
+                }
+                let fb = self.fb.lock().unwrap();
+                if !self.local_zoom && !fb.transform().overflows(fb.surface_size) {
+                    return Pan::Pass;
+                }
+                let (midpoint, spread) = geometry.unwrap();
+                self.pan_gesture = Some(PanGesture { active: false, start: midpoint, start_spread: spread, midpoint, spread });
+                Pan::Pass
+            }
+        }
+    }
+
+    /// Undoes any local zoom and panning.
+    pub fn reset_view(&mut self) {
+        let mut fb = self.fb.lock().unwrap();
+        fb.zoom = 1.0;
+        fb.pan = (0.0, 0.0);
+        fb.update_transform();
+    }
+
+    pub fn rotation(&self) -> Rotation {
+        self.fb.lock().unwrap().rotation
+    }
+
+    /// Turns the mirrored display, e.g. to follow the device when the compositor's orientation is
+    /// locked.
+    pub fn set_rotation(&mut self, rotation: Rotation, scale_factor: f64) {
+        let mut fb = self.fb.lock().unwrap();
+        info!("rotating display to {:?}", rotation);
+        fb.rotation = rotation;
+        fb.update_transform();
+        self.update_native(&mut fb, scale_factor);
+    }
+
+    fn update_native(&self, fb: &mut Framebuffer, scale_factor: f64) {
+        let native = Mode::for_window(fb.surface_size, self.layout, fb.rotation);
+        if native != fb.native {
+            info!("native mode is now {}x{}", native.width, native.height);
+            fb.native = native;
+            fb.hotplug = true;
+        }
+        // The host only re-reads the EDID, and with it the physical size, on a hotplug.
+        if scale_factor != fb.scale_factor {
+            fb.scale_factor = scale_factor;
+            fb.hotplug = true;
+        }
+    }
+}
+
+/// Midpoint of all the touches, and their average distance from it.
+fn pan_geometry(touches: &HashMap<u64, PhysicalPosition<f64>>) -> Option<(PhysicalPosition<f64>, f64)> {
+    if touches.is_empty() {
+        return None;
+    }
+    let count = touches.len() as f64;
+    let midpoint = PhysicalPosition::new(
+        touches.values().map(|pos| pos.x).sum::<f64>() / count,
+        touches.values().map(|pos| pos.y).sum::<f64>() / count,
+    );
+    let spread = touches.values().map(|pos| (pos.x - midpoint.x).hypot(pos.y - midpoint.y)).sum::<f64>() / count;
+    Some((midpoint, spread))
+}
+
+/// The rect covering every pixel an overlay touched.
+fn saved_bounds(saved: &[(usize, [u8; 4])], mode: Mode) -> Option<Rect> {
+    saved
+        .iter()
+        .map(|(offset, _)| {
+            let pixel = (*offset / 4) as u32;
+            Rect { x: pixel % mode.width, y: pixel / mode.width, width: 1, height: 1 }
+        })
+        .reduce(Rect::union)
+}
+
+/// Shows which part of the turned buffer is in view when zoomed or panned, in the top left corner
+/// of the window. It's laid out in window pixels so it stays upright and the same size however the
+/// view is turned and zoomed. Returns the rects to fill, in drawing order.
+fn minimap(transform: ViewTransform, surface: PhysicalSize<u32>) -> Vec<(Rect, [u8; 4])> {
+    let (width, height) = transform.extent();
+    let scale = MINIMAP_SIZE / width.max(height);
+    let map = Rect {
+        x: MINIMAP_MARGIN as u32,
+        y: MINIMAP_MARGIN as u32,
+        width: (width * scale).round().max(3.0) as u32,
+        height: (height * scale).round().max(3.0) as u32,
+    };
+
+    // The part of the turned buffer the window covers.
+    let left = (-transform.offset.0).clamp(0.0, width);
+    let top = (-transform.offset.1).clamp(0.0, height);
+    let right = (surface.width as f64 - transform.offset.0).clamp(0.0, width);
+    let bottom = (surface.height as f64 - transform.offset.1).clamp(0.0, height);
+    let x = ((left * scale).round() as u32).min(map.width - 3);
+    let y = ((top * scale).round() as u32).min(map.height - 3);
+    let view = Rect {
+        x: map.x + x,
+        y: map.y + y,
+        width: ((right * scale).round() as u32).clamp(x + 3, map.width) - x,
+        height: ((bottom * scale).round() as u32).clamp(y + 3, map.height) - y,
+    };
+
+    vec![
+        (map, TRACKPAD_EDGE_COLOR),
+        (inset(map), TRACKPAD_COLOR),
+        (view, MINIMAP_VIEWPORT_COLOR),
+        (inset(view), TRACKPAD_COLOR),
+    ]
+}
+
+/// The rect one pixel in from each edge, leaving a border.
+fn inset(rect: Rect) -> Rect {
+    Rect {
+        x: rect.x + 1,
+        y: rect.y + 1,
+        width: rect.width.saturating_sub(2),
+        height: rect.height.saturating_sub(2),
+    }
+}
+
+/// Sets a framebuffer pixel for an overlay, remembering what was there in `saved`.
+fn put_overlay_pixel(frame: &mut [u8], mode: Mode, saved: &mut Vec<(usize, [u8; 4])>, x: i32, y: i32, color: [u8; 4]) {
+    if x < 0 || y < 0 || x >= mode.width as i32 || y >= mode.height as i32 {
+        return;
+    }
+    let offset = ((y as u32 * mode.width + x as u32) * 4) as usize;
+    let mut old = [0; 4];
+    old.copy_from_slice(&frame[offset..offset + 4]);
+    saved.push((offset, old));
+    frame[offset..offset + 4].copy_from_slice(&color);
+}
+
+/// Draws a ring marker centred on the given framebuffer position.
+fn draw_cursor_overlay(frame: &mut [u8], mode: Mode, saved: &mut Vec<(usize, [u8; 4])>, pos: PhysicalPosition<f64>) {
+    let (cx, cy) = (pos.x as i32, pos.y as i32);
+    for dy in -CURSOR_OVERLAY_RADIUS..=CURSOR_OVERLAY_RADIUS {
+        for dx in -CURSOR_OVERLAY_RADIUS..=CURSOR_OVERLAY_RADIUS {
+            // White ring with a black outline, so it's visible on any background.
+            let distance = ((dx * dx + dy * dy) as f64).sqrt().round() as i32;
+            let color = if distance == CURSOR_OVERLAY_RADIUS - 1 {
+                [0xFF, 0xFF, 0xFF, 0xFF]
+            } else if distance == CURSOR_OVERLAY_RADIUS || distance == CURSOR_OVERLAY_RADIUS - 2 {
+                [0x00, 0x00, 0x00, 0xFF]
+            } else {
+                continue;
+            };
+            put_overlay_pixel(frame, mode, saved, cx + dx, cy + dy, color);
+        }
+    }
+}
+
+/// Draws a border around the host framebuffer, so it's obvious a tap is expected, and a crosshair
+/// through the given position.
+fn draw_calibration_prompt(frame: &mut [u8], mode: Mode, saved: &mut Vec<(usize, [u8; 4])>, pos: PhysicalPosition<f64>) {
+    let (cx, cy) = (pos.x as u32, pos.y as u32);
+    for y in 0..mode.height {
+        for x in 0..mode.width {
+            let on_border = x < CALIBRATION_BORDER
+                || y < CALIBRATION_BORDER
+                || x >= mode.width - CALIBRATION_BORDER
+                || y >= mode.height - CALIBRATION_BORDER;
+            if on_border || x == cx || y == cy {
+                put_overlay_pixel(frame, mode, saved, x as i32, y as i32, CALIBRATION_COLOR);
+            }
+        }
     }
 }
 
-fn run(mut gud_func: Custom, mut gud_data: PixelDataEndpoint, pixels: Arc<Mutex<Pixels>>, events: EventLoopProxy<AppEvent>) {
+/// Draws a magnified copy of the framebuffer around `pos` in a square above it, with a crosshair
+/// marking the exact point.
+fn draw_loupe(frame: &mut [u8], mode: Mode, saved: &mut Vec<(usize, [u8; 4])>, pos: PhysicalPosition<f64>) {
+    let (cx, cy) = (pos.x as i32, pos.y as i32);
+    let half = LOUPE_SIZE / 2;
+    // Flip below the finger when there's no room above it.
+    let loupe_cy = if cy - LOUPE_OFFSET - half >= 0 { cy - LOUPE_OFFSET } else { cy + LOUPE_OFFSET };
+
+    // Sample the source first, the loupe may overlap the area it magnifies.
+    let mut magnified = Vec::with_capacity((LOUPE_SIZE * LOUPE_SIZE) as usize);
+    for dy in -half..half {
+        for dx in -half..half {
+            let (x, y) = (
+                (cx + dx / LOUPE_ZOOM).clamp(0, mode.width as i32 - 1),
+                (cy + dy / LOUPE_ZOOM).clamp(0, mode.height as i32 - 1),
+            );
+            let offset = ((y as u32 * mode.width + x as u32) * 4) as usize;
+            let mut color = [0; 4];
+            color.copy_from_slice(&frame[offset..offset + 4]);
+            magnified.push(color);
+        }
+    }
+
+    for (i, color) in magnified.into_iter().enumerate() {
+        let (dx, dy) = (i as i32 % LOUPE_SIZE - half, i as i32 / LOUPE_SIZE - half);
+        let on_edge = dx == -half || dy == -half || dx == half - 1 || dy == half - 1;
+        let on_crosshair = (dx == 0 || dy == 0) && dx.abs() < LOUPE_ZOOM * 2 && dy.abs() < LOUPE_ZOOM * 2;
+        let color = if on_edge || on_crosshair { TRACKPAD_EDGE_COLOR } else { color };
+        put_overlay_pixel(frame, mode, saved, cx + dx, loupe_cy + dy, color);
+    }
+}
+
+/// Draws the virtual trackpad, and its click buttons if there are any, below the host framebuffer.
+fn draw_trackpad(frame: &mut [u8], mode: Mode, buttons: usize) {
+    let trackpad_height = trackpad_height(mode.height);
+    let button_top = mode.height + trackpad_height - trackpad_height / TRACKPAD_BUTTON_HEIGHT_DEN;
+    for y in mode.height..mode.height + trackpad_height {
+        for x in 0..mode.width {
+            let in_buttons = buttons > 0 && y >= button_top;
+            let on_edge = y == mode.height
+                || (in_buttons && y == button_top)
+                || (in_buttons && x > 0 && (x * buttons as u32) / mode.width != ((x - 1) * buttons as u32) / mode.width);
+            let color = if on_edge {
+                TRACKPAD_EDGE_COLOR
+            } else if in_buttons {
+                TRACKPAD_BUTTON_COLOR
+            } else {
+                TRACKPAD_COLOR
+            };
+            let offset = ((y * mode.width + x) * 4) as usize;
+            frame[offset..offset + 4].copy_from_slice(&color);
+        }
+    }
+}
+
+/// Copies converted pixels for a rect of the host's buffer into the frame, turned the way the host
+/// asked, and returns the rect of the frame they cover.
+fn copy_turned(frame: &mut [u8], frame_width: u32, src: &[u8], rect: Rect, rotation: Rotation, buffer: PhysicalSize<u32>) -> Rect {
+    for (i, pixel) in src.chunks_exact(4).enumerate() {
+        let (x, y) = (rect.x + i as u32 % rect.width, rect.y + i as u32 / rect.width);
+        let (x, y) = rotation.turn_point(x, y, buffer);
+        let offset = ((y * frame_width + x) * 4) as usize;
+        frame[offset..offset + 4].copy_from_slice(pixel);
+    }
+    rotation.turn_rect(rect, buffer)
+}
+
+fn run(mut gud_func: Custom, mut gud_data: PixelDataEndpoint, fb: Arc<Mutex<Framebuffer>>, events: EventLoopProxy<AppEvent>) {
+    let serial = edid::machine_serial();
+    let mut gud = Gud::default();
     loop {
         if let Ok(Some(event)) = gud_func.event_timeout(Duration::from_millis(100)) {
-            println!("yee: {:?}", event);
-            if let Ok(Some(gud_event)) = gud_gadget::event(event) {
-                println!("yee: {:?}", gud_event);
+            trace!("gud function event {:?}", event);
+            if let Ok(Some(gud_event)) = gud.event(event) {
+                trace!("gud request {:?}", gud_event);
                 match gud_event {
                     Event::GetDescriptor(req) => {
-                        req.send_descriptor(WIDTH, HEIGHT, WIDTH, HEIGHT).expect("failed to send descriptor");
+                        // LZ4 makes full screen video usable over USB 2.0.
+                        req.send_descriptor(MIN_DIMENSION, MIN_DIMENSION, MAX_DIMENSION, MAX_DIMENSION, gud::GUD_COMPRESSION_LZ4).expect("failed to send descriptor");
                     }
                     Event::GetPixelFormats(req) => {
-                        req.send_pixel_formats(&[gud_gadget::GUD_PIXEL_FORMAT_XRGB8888]).unwrap()
+                        req.send_pixel_formats(&PixelFormat::ALL.map(PixelFormat::gud)).unwrap()
                     }
                     Event::GetDisplayModes(req) => {
-                        req.send_modes(&[gud_gadget::DisplayMode {
-                            clock: WIDTH * HEIGHT * 60 / 1000,
-                            hdisplay: WIDTH as u16,
-                            htotal: WIDTH as u16,
-                            hsync_end: WIDTH as u16,
-                            hsync_start: WIDTH as u16,
-                            vtotal: HEIGHT as u16,
-                            vdisplay: HEIGHT as u16,
-                            vsync_end: HEIGHT as u16,
-                            vsync_start: HEIGHT as u16,
-                            flags: 0,
-                        }]).expect("failed to send modes");
+                        // An unplugged monitor has no modes, in case the host asks anyway.
+                        let (native, connected) = {
+                            let fb = fb.lock().unwrap();
+                            (fb.native, fb.connected)
+                        };
+                        let modes = if connected {
+                            modes::display_modes((native.width, native.height), MAX_DIMENSION)
+                        } else {
+                            vec![]
+                        };
+                        req.send_modes(&modes).expect("failed to send modes");
+                    }
+                    Event::SetState(state) => {
+                        // Whichever mode the host picked is scaled into the window.
+                        let mut fb = fb.lock().unwrap();
+                        let mode = Mode {
+                            width: state.mode.hdisplay as u32,
+                            height: state.mode.vdisplay as u32,
+                        };
+                        fb.set_mode(mode);
+                        // Every state carries all the properties, only pass on changes.
+                        fb.host_rotation = Rotation::R0;
+                        for &(property, value) in &state.properties {
+                            if property == gud::GUD_PROPERTY_ROTATION {
+                                match Rotation::from_gud(value) {
+                                    Some(rotation) => fb.host_rotation = rotation,
+                                    None => error!("host picked unsupported rotation {:#x}", value),
+                                }
+                            }
+                            if property == gud::GUD_PROPERTY_BACKLIGHT_BRIGHTNESS && fb.backlight.is_some() {
+                                // A stale value from before a change on the device isn't applied.
+                                let percent = value.min(100) as u8;
+                                let host_changed = fb.host_backlight.replace(percent) != Some(percent);
+                                if host_changed && fb.backlight != Some(percent) {
+                                    fb.backlight = Some(percent);
+                                    events.send_event(AppEvent::BacklightSet(percent)).unwrap();
+                                }
+                            }
+                        }
+                        match PixelFormat::from_gud(state.format) {
+                            Some(format) => fb.format = format,
+                            None => error!("host picked unsupported pixel format {:#x}", state.format),
+                        }
+                        events.send_event(AppEvent::DisplayModeChanged).unwrap();
+                    }
+                    Event::GetProperties(req) => {
+                        // Lets the host rotate the monitor without turning every frame itself.
+                        let rotations = gud::GUD_ROTATION_0 | gud::GUD_ROTATION_90 | gud::GUD_ROTATION_180 | gud::GUD_ROTATION_270;
+                        req.send_properties(&[(gud::GUD_PROPERTY_ROTATION, rotations)])
+                            .expect("failed to send properties");
+                    }
+                    Event::GetEdid(req) => {
+                        let (native, scale_factor) = {
+                            let fb = fb.lock().unwrap();
+                            (fb.native, fb.scale_factor)
+                        };
+                        let edid = edid::generate(
+                            &modes::cvt_rb(native.width, native.height, true),
+                            native.size_mm(scale_factor),
+                            serial,
+                        );
+                        req.send_edid(&edid).expect("failed to send EDID");
+                    }
+                    Event::GetConnectorProperties(req) => {
+                        // The backlight is only offered when the device has one to control.
+                        let backlight = {
+                            let mut fb = fb.lock().unwrap();
+                            fb.host_backlight = fb.backlight;
+                            fb.backlight
+                        };
+                        let properties: Vec<_> = backlight
+                            .map(|percent| (gud::GUD_PROPERTY_BACKLIGHT_BRIGHTNESS, percent as u64))
+                            .into_iter()
+                            .collect();
+                        req.send_properties(&properties).expect("failed to send connector properties");
+                    }
+                    Event::GetConnectorStatus(req) => {
+                        // The host polls the connector status, a change makes it re-read the modes.
+                        let mut fb = fb.lock().unwrap();
+                        let changed = std::mem::take(&mut fb.hotplug);
+                        req.send_status(fb.connected, changed).expect("failed to send connector status");
                     }
                     Event::Buffer(info) => {
-                        gud_data.recv_buffer(info, pixels.lock().unwrap().frame_mut(), (WIDTH * 4) as usize, 4).expect("recv_buffer failed");
+                        let mut fb = fb.lock().unwrap();
+                        let Framebuffer { frame, mode, format, staging, compressed, turned, host_rotation, .. } = &mut *fb;
+                        staging.resize(info.length as usize, 0);
+                        let received = if info.compression == 0 {
+                            gud_data.recv_raw(staging)
+                        } else {
+                            compressed.resize(info.transfer_len(), 0);
+                            gud_data.recv_raw(compressed).and_then(|_| gud::decompress(compressed, staging))
+                        };
+                        if let Err(err) = received {
+                            error!("failed to receive buffer: {:#}", err);
+                            continue;
+                        }
+                        let rect = Rect { x: info.x, y: info.y, width: info.width, height: info.height };
+                        // When rotating, the host's buffer is the mode turned back the other way.
+                        let buffer = host_rotation.apply(PhysicalSize::new(mode.width, mode.height));
+                        // Frames sized for the previous mode can still be in flight after a resize.
+                        if rect.width == 0
+                            || rect.height == 0
+                            || rect.x + rect.width > buffer.width
+                            || rect.y + rect.height > buffer.height
+                            || staging.len() < format.line_len(rect.width as usize) * rect.height as usize
+                        {
+                            error!("dropping {}x{}+{}+{} buffer that doesn't fit the mode", rect.width, rect.height, rect.x, rect.y);
+                            continue;
+                        }
+                        // Each flush only covers what changed on the host, the renderer uploads the
+                        // accumulated damage on the next redraw.
+                        let damage = if *host_rotation == Rotation::R0 {
+                            format.convert(staging, rect, frame, mode.width as usize);
+                            rect
+                        } else {
+                            turned.resize((rect.width * rect.height * 4) as usize, 0);
+                            format.convert(staging, Rect { x: 0, y: 0, ..rect }, turned, rect.width as usize);
+                            copy_turned(frame, mode.width, turned, rect, *host_rotation, buffer)
+                        };
+                        fb.damage(damage);
                         events.send_event(AppEvent::DisplayFrameArrived).unwrap();
                     }
                 }
@@ -107,3 +1075,85 @@ fn run(mut gud_func: Custom, mut gud_data: PixelDataEndpoint, pixels: Arc<Mutex<
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gud_rotation_is_counter_clockwise() {
+        assert_eq!(Rotation::from_gud(gud::GUD_ROTATION_90), Some(Rotation::R270));
+        assert_eq!(Rotation::from_gud(gud::GUD_ROTATION_270), Some(Rotation::R90));
+        assert_eq!(Rotation::from_gud(gud::GUD_ROTATION_0 | gud::GUD_ROTATION_90), None);
+    }
+
+    #[test]
+    fn pan_geometry_spread() {
+        let touches = HashMap::from([
+            (1, PhysicalPosition::new(0.0, 0.0)),
+            (2, PhysicalPosition::new(6.0, 0.0)),
+            (3, PhysicalPosition::new(3.0, 4.0)),
+        ]);
+        let (midpoint, spread) = pan_geometry(&touches).unwrap();
+        assert_eq!((midpoint.x, midpoint.y), (3.0, 4.0 / 3.0));
+        // Pinching in scales the spread with the fingers.
+        let pinched: HashMap<_, _> = touches
+            .iter()
+            .map(|(&id, pos)| (id, PhysicalPosition::new(3.0 + (pos.x - 3.0) / 2.0, 4.0 / 3.0 + (pos.y - 4.0 / 3.0) / 2.0)))
+            .collect();
+        assert!((pan_geometry(&pinched).unwrap().1 - spread / 2.0).abs() < 1e-9);
+        assert!(pan_geometry(&HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn minimap_stays_upright() {
+        let surface = PhysicalSize::new(1000, 500);
+        let transform = ViewTransform {
+            scale: 2.0,
+            offset: (-1000.0, 0.0),
+            rotation: Rotation::R0,
+            buffer: PhysicalSize::new(1000, 500),
+        };
+        let fills = minimap(transform, surface);
+        assert_eq!(fills[0].0, Rect { x: 12, y: 12, width: 120, height: 60 });
+        // Panned to the right half.
+        assert_eq!(fills[2].0, Rect { x: 72, y: 12, width: 60, height: 30 });
+
+        // Turned a quarter, the map is as tall as the view is and the window still covers its
+        // top right.
+        let transform = ViewTransform { offset: (-500.0, 0.0), rotation: Rotation::R90, ..transform };
+        let fills = minimap(transform, surface);
+        assert_eq!(fills[0].0, Rect { x: 12, y: 12, width: 60, height: 120 });
+        assert_eq!(fills[2].0, Rect { x: 42, y: 12, width: 30, height: 30 });
+    }
+
+    #[test]
+    fn turning_moves_corners() {
+        // A 4x2 buffer turned a quarter clockwise is 2x4, its top left corner at the top right.
+        let size = PhysicalSize::new(4, 2);
+        assert_eq!(Rotation::R90.turn_point(0, 0, size), (1, 0));
+        assert_eq!(Rotation::R180.turn_point(0, 0, size), (3, 1));
+        assert_eq!(Rotation::R270.turn_point(0, 0, size), (0, 3));
+        assert_eq!(Rotation::R270.turn_point(3, 1, size), (1, 0));
+    }
+
+    #[test]
+    fn turned_rects_cover_turned_pixels() {
+        let size = PhysicalSize::new(4, 2);
+        let rect = Rect { x: 1, y: 0, width: 2, height: 1 };
+        for rotation in [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270] {
+            let mut frame = vec![0; 4 * 2 * 4];
+            let turned_size = rotation.apply(size);
+            let covered = copy_turned(&mut frame, turned_size.width, &[0xFF; 2 * 4], rect, rotation, size);
+            let expected: Vec<usize> = (0..8)
+                .filter(|i| {
+                    let (x, y) = (i % turned_size.width as usize, i / turned_size.width as usize);
+                    (covered.x as usize..(covered.x + covered.width) as usize).contains(&x)
+                        && (covered.y as usize..(covered.y + covered.height) as usize).contains(&y)
+                })
+                .collect();
+            let written: Vec<usize> = (0..8).filter(|i| frame[i * 4] == 0xFF).collect();
+            assert_eq!(written, expected, "{:?}", rotation);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::gud::{DisplayMode, GUD_DISPLAY_MODE_FLAG_NVSYNC, GUD_DISPLAY_MODE_FLAG_PHSYNC};
+
+pub const EDID_LEN: usize = 128;
+
+/// Shown by hosts in their display settings.
+const PRODUCT_NAME: &str = "usb-kvm";
+/// Three letter PNP manufacturer ID.
+const MANUFACTURER: &[u8; 3] = b"UKV";
+const PRODUCT_CODE: u16 = 0x0001;
+const GAMMA_2_2: u8 = 120;
+
+/// sRGB primaries and D65 white point, packed as EDID chromaticity coordinates.
+const SRGB_CHROMATICITY: [u8; 10] = [0xEE, 0x91, 0xA3, 0x54, 0x4C, 0x99, 0x26, 0x0F, 0x50, 0x54];
+
+const DESCRIPTOR_SERIAL: u8 = 0xFF;
+const DESCRIPTOR_NAME: u8 = 0xFC;
+const DESCRIPTOR_DUMMY: u8 = 0x10;
+
+/// Keys the machine ID into this app's serial, so the serial doesn't give the ID away. Like
+/// systemd's sd_id128_get_machine_app_specific(), and as random.
+const APP_ID: [u8; 16] = [0x4C, 0x34, 0xC1, 0xDF, 0xAB, 0xB6, 0x9C, 0xA4, 0xDB, 0x4F, 0x43, 0x72, 0x4E, 0x99, 0x6F, 0x08];
+
+/// Generates an EDID 1.4 base block describing the local panel, so hosts can pick a sensible
+/// scaling factor from its physical size.
+pub fn generate(preferred: &DisplayMode, size_mm: (u32, u32), serial: u32) -> [u8; EDID_LEN] {
+    let mut edid = [0; EDID_LEN];
+    edid[0..8].copy_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+
+    // Letters are packed as 5 bit values, 'A' being 1.
+    let [a, b, c] = MANUFACTURER.map(|l| (l - b'A' + 1) as u16);
+    edid[8..10].copy_from_slice(&(a << 10 | b << 5 | c).to_be_bytes());
+    edid[10..12].copy_from_slice(&PRODUCT_CODE.to_le_bytes());
+    edid[12..16].copy_from_slice(&serial.to_le_bytes());
+    // No week of manufacture, model year 2024.
+    edid[16] = 0xFF;
+    edid[17] = (2024 - 1990) as u8;
+    edid[18] = 1;
+    edid[19] = 4;
+
+    // Digital input, 8 bits per colour.
+    edid[20] = 0x80 | 0x20;
+    // Centimetres, rounded.
+    edid[21] = ((size_mm.0 + 5) / 10).min(255) as u8;
+    edid[22] = ((size_mm.1 + 5) / 10).min(255) as u8;
+    edid[23] = GAMMA_2_2;
+    // sRGB is the default colour space, and the preferred timing is the native mode.
+    edid[24] = 0x04 | 0x02;
+    edid[25..35].copy_from_slice(&SRGB_CHROMATICITY);
+
+    // No established timings, and all standard timings unused.
+    for timing in edid[38..54].chunks_exact_mut(2) {
+        timing.copy_from_slice(&[0x01, 0x01]);
+    }
+
+    edid[54..72].copy_from_slice(&detailed_timing(preferred, size_mm));
+    edid[72..90].copy_from_slice(&text_descriptor(DESCRIPTOR_NAME, PRODUCT_NAME));
+    edid[90..108].copy_from_slice(&text_descriptor(DESCRIPTOR_SERIAL, &format!("{:08X}", serial)));
+    edid[108..126].copy_from_slice(&descriptor(DESCRIPTOR_DUMMY));
+
+    // No extension blocks.
+    edid[126] = 0;
+    edid[127] = checksum(&edid[..127]);
+    edid
+}
+
+/// The byte that makes the whole block sum to 0 mod 256.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+fn detailed_timing(mode: &DisplayMode, size_mm: (u32, u32)) -> [u8; 18] {
+    let hblank = mode.htotal - mode.hdisplay;
+    let vblank = mode.vtotal - mode.vdisplay;
+    let hsync_offset = mode.hsync_start - mode.hdisplay;
+    let hsync_width = mode.hsync_end - mode.hsync_start;
+    let vsync_offset = mode.vsync_start - mode.vdisplay;
+    let vsync_width = mode.vsync_end - mode.vsync_start;
+    let (width_mm, height_mm) = (size_mm.0.min(0xFFF) as u16, size_mm.1.min(0xFFF) as u16);
+
+    // Digital separate sync, with the polarities from the mode.
+    let mut flags = 0x18;
+    if mode.flags & GUD_DISPLAY_MODE_FLAG_NVSYNC == 0 {
+        flags |= 0x04;
+    }
+    if mode.flags & GUD_DISPLAY_MODE_FLAG_PHSYNC != 0 {
+        flags |= 0x02;
+    }
+
+    // In 10 kHz units, which tops out at 655.35 MHz. Hosts take their modes from GUD rather than
+    // the EDID, so a faster clock is only understated instead of wrapping around to a slow one.
+    let clock = (mode.clock / 10).min(u16::MAX as u32) as u16;
+
+    // Each value is split into its low byte and its high bits, which share bytes with others.
+    let clock = clock.to_le_bytes();
+    [
+        clock[0],
+        clock[1],
+        mode.hdisplay as u8,
+        hblank as u8,
+        ((mode.hdisplay >> 8) as u8) << 4 | (hblank >> 8) as u8,
+        mode.vdisplay as u8,
+        vblank as u8,
+        ((mode.vdisplay >> 8) as u8) << 4 | (vblank >> 8) as u8,
+        hsync_offset as u8,
+        hsync_width as u8,
+        (vsync_offset as u8 & 0x0F) << 4 | vsync_width as u8 & 0x0F,
+        ((hsync_offset >> 8) as u8 & 0x03) << 6
+            | ((hsync_width >> 8) as u8 & 0x03) << 4
+            | ((vsync_offset >> 4) as u8 & 0x03) << 2
+            | (vsync_width >> 4) as u8 & 0x03,
+        width_mm as u8,
+        height_mm as u8,
+        ((width_mm >> 8) as u8) << 4 | (height_mm >> 8) as u8,
+        0,
+        0,
+        flags,
+    ]
+}
+
+fn descriptor(tag: u8) -> [u8; 18] {
+    let mut descriptor = [0; 18];
+    descriptor[3] = tag;
+    descriptor
+}
+
+/// A descriptor holding up to 13 characters of text, terminated by a newline and padded with
+/// spaces if shorter.
+fn text_descriptor(tag: u8, text: &str) -> [u8; 18] {
+    let mut descriptor = descriptor(tag);
+    descriptor[5..].fill(b' ');
+    let text = &text.as_bytes()[..text.len().min(13)];
+    descriptor[5..5 + text.len()].copy_from_slice(text);
+    if text.len() < 13 {
+        descriptor[5 + text.len()] = b'\n';
+    }
+    descriptor
+}
+
+/// A serial number that stays the same across runs on this device, so the host remembers its
+/// settings for the monitor.
+pub fn machine_serial() -> u32 {
+    std::fs::read_to_string("/etc/machine-id")
+        .ok()
+        .and_then(|id| u128::from_str_radix(id.trim(), 16).ok())
+        .map(app_specific_serial)
+        .unwrap_or(0)
+}
+
+/// HMAC-SHA256 of [`APP_ID`] keyed by the machine ID, cut down to 32 bits.
+fn app_specific_serial(machine_id: u128) -> u32 {
+    let mac = hmac_sha256::HMAC::mac(APP_ID, machine_id.to_be_bytes());
+    u32::from_le_bytes(mac[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modes;
+
+    fn text(descriptor: &[u8]) -> &str {
+        std::str::from_utf8(&descriptor[5..]).unwrap().split('\n').next().unwrap()
+    }
+
+    #[test]
+    fn block_sums_to_zero() {
+        let edid = generate(&modes::cvt_rb(1920, 1080, true), (344, 194), 0x1234ABCD);
+        assert_eq!(edid.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+    }
+
+    #[test]
+    fn descriptors_round_trip() {
+        let mode = modes::cvt_rb(2560, 1600, true);
+        let edid = generate(&mode, (302, 189), 0x1234ABCD);
+
+        assert_eq!(&edid[..8], &[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+        assert_eq!(u32::from_le_bytes(edid[12..16].try_into().unwrap()), 0x1234ABCD);
+        assert_eq!((edid[21], edid[22]), (30, 19));
+
+        let dtd = &edid[54..72];
+        assert_eq!(u16::from_le_bytes([dtd[0], dtd[1]]) as u32, mode.clock / 10);
+        let high = |byte: u8, shift: u8| (byte >> shift & 0x0F) as u16;
+        assert_eq!(dtd[2] as u16 | high(dtd[4], 4) << 8, mode.hdisplay);
+        assert_eq!(dtd[3] as u16 | high(dtd[4], 0) << 8, mode.htotal - mode.hdisplay);
+        assert_eq!(dtd[5] as u16 | high(dtd[7], 4) << 8, mode.vdisplay);
+        assert_eq!(dtd[6] as u16 | high(dtd[7], 0) << 8, mode.vtotal - mode.vdisplay);
+        assert_eq!(dtd[8] as u16 | ((dtd[11] >> 6 & 0x03) as u16) << 8, mode.hsync_start - mode.hdisplay);
+        assert_eq!(dtd[9] as u16 | ((dtd[11] >> 4 & 0x03) as u16) << 8, mode.hsync_end - mode.hsync_start);
+        assert_eq!(high(dtd[10], 4) | ((dtd[11] >> 2 & 0x03) as u16) << 4, mode.vsync_start - mode.vdisplay);
+        assert_eq!(high(dtd[10], 0) | ((dtd[11] & 0x03) as u16) << 4, mode.vsync_end - mode.vsync_start);
+        assert_eq!(dtd[12] as u16 | high(dtd[14], 4) << 8, 302);
+        assert_eq!(dtd[13] as u16 | high(dtd[14], 0) << 8, 189);
+        // CVT reduced blanking syncs are +hsync -vsync.
+        assert_eq!(dtd[17], 0x18 | 0x02);
+
+        assert_eq!(edid[75], DESCRIPTOR_NAME);
+        assert_eq!(text(&edid[72..90]), PRODUCT_NAME);
+        assert_eq!(edid[93], DESCRIPTOR_SERIAL);
+        assert_eq!(text(&edid[90..108]), "1234ABCD");
+    }
+
+    #[test]
+    fn fast_clock_is_clamped() {
+        let mode = modes::cvt_rb(7680, 4320, true);
+        assert!(mode.clock > 655_350);
+        assert_eq!(&detailed_timing(&mode, (0, 0))[..2], &[0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn serial_hides_machine_id() {
+        let machine_id = 0x0123456789ABCDEF0123456789ABCDEF;
+        let serial = app_specific_serial(machine_id);
+        assert_eq!(serial, app_specific_serial(machine_id));
+        assert_ne!(serial, 0x01234567);
+        assert_ne!(serial, app_specific_serial(machine_id + 1));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::gud::{
+    GUD_PIXEL_FORMAT_R1, GUD_PIXEL_FORMAT_R8, GUD_PIXEL_FORMAT_RGB565, GUD_PIXEL_FORMAT_RGB888,
+    GUD_PIXEL_FORMAT_XRGB8888,
+};
+use crate::renderer::Rect;
+
+/// The pixel formats the host may send frames in. Smaller formats trade colour depth for frame
+/// rate on slow USB links.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// 1bpp monochrome, most significant bit first.
+    R1,
+    /// 8bpp greyscale.
+    R8,
+    Rgb565,
+    Rgb888,
+    Xrgb8888,
+}
+
+impl PixelFormat {
+    /// Advertised to the host in order of preference.
+    pub const ALL: [PixelFormat; 5] = [
+        PixelFormat::Xrgb8888,
+        PixelFormat::Rgb888,
+        PixelFormat::Rgb565,
+        PixelFormat::R8,
+        PixelFormat::R1,
+    ];
+
+    pub fn from_gud(format: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.gud() == format)
+    }
+
+    pub fn gud(self) -> u8 {
+        match self {
+            PixelFormat::R1 => GUD_PIXEL_FORMAT_R1,
+            PixelFormat::R8 => GUD_PIXEL_FORMAT_R8,
+            PixelFormat::Rgb565 => GUD_PIXEL_FORMAT_RGB565,
+            PixelFormat::Rgb888 => GUD_PIXEL_FORMAT_RGB888,
+            PixelFormat::Xrgb8888 => GUD_PIXEL_FORMAT_XRGB8888,
+        }
+    }
+
+    fn bits_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::R1 => 1,
+            PixelFormat::R8 => 8,
+            PixelFormat::Rgb565 => 16,
+            PixelFormat::Rgb888 => 24,
+            PixelFormat::Xrgb8888 => 32,
+        }
+    }
+
+    /// Length in bytes of one line of `width` pixels. The host packs the lines of a rect without
+    /// any padding beyond rounding up to a whole byte.
+    pub fn line_len(self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Decodes the pixel at `x` in a line into the frame's XRGB8888 byte order.
+    fn pixel(self, line: &[u8], x: usize) -> [u8; 4] {
+        match self {
+            PixelFormat::R1 => {
+                let v = if line[x / 8] & (0x80 >> (x % 8)) != 0 { 0xFF } else { 0 };
+                [v, v, v, 0xFF]
+            }
+            PixelFormat::R8 => [line[x], line[x], line[x], 0xFF],
+            PixelFormat::Rgb565 => {
+                let v = u16::from_le_bytes([line[x * 2], line[x * 2 + 1]]);
+                // Replicate the high bits into the low ones so white stays white.
+                let r = (v >> 11) as u8 & 0x1F;
+                let g = (v >> 5) as u8 & 0x3F;
+                let b = v as u8 & 0x1F;
+                [b << 3 | b >> 2, g << 2 | g >> 4, r << 3 | r >> 2, 0xFF]
+            }
+            // Little endian, so the bytes are in blue, green, red order already.
+            PixelFormat::Rgb888 => [line[x * 3], line[x * 3 + 1], line[x * 3 + 2], 0xFF],
+            PixelFormat::Xrgb8888 => line[x * 4..x * 4 + 4].try_into().unwrap(),
+        }
+    }
+
+    /// Converts a packed rect of pixels in this format into the frame, which is XRGB8888 and
+    /// `frame_width` pixels wide.
+    pub fn convert(self, src: &[u8], rect: Rect, frame: &mut [u8], frame_width: usize) {
+        let (x, y, width) = (rect.x as usize, rect.y as usize, rect.width as usize);
+        let line_len = self.line_len(width);
+        for (row, line) in src.chunks_exact(line_len).take(rect.height as usize).enumerate() {
+            let offset = ((y + row) * frame_width + x) * 4;
+            let Some(dst) = frame.get_mut(offset..offset + width * 4) else {
+                return;
+            };
+            // The frame is in the host's byte order, so the common case is a straight copy.
+            if self == PixelFormat::Xrgb8888 {
+                dst.copy_from_slice(line);
+                continue;
+            }
+            for (col, pixel) in dst.chunks_exact_mut(4).enumerate() {
+                pixel.copy_from_slice(&self.pixel(line, col));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts a single line of `width` pixels into a frame of the same width.
+    fn convert_line(format: PixelFormat, src: &[u8], width: u32) -> Vec<u8> {
+        let mut frame = vec![0; width as usize * 4];
+        format.convert(src, Rect { x: 0, y: 0, width, height: 1 }, &mut frame, width as usize);
+        frame
+    }
+
+    #[test]
+    fn xrgb8888_is_copied() {
+        let src = [0x10, 0x20, 0x30, 0x00, 0x40, 0x50, 0x60, 0x00];
+        assert_eq!(convert_line(PixelFormat::Xrgb8888, &src, 2), src);
+    }
+
+    #[test]
+    fn smaller_formats_expand_to_xrgb8888() {
+        assert_eq!(convert_line(PixelFormat::Rgb888, &[0x10, 0x20, 0x30], 1), [0x10, 0x20, 0x30, 0xFF]);
+        // Pure red and white.
+        assert_eq!(
+            convert_line(PixelFormat::Rgb565, &[0x00, 0xF8, 0xFF, 0xFF], 2),
+            [0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+        );
+        assert_eq!(convert_line(PixelFormat::R8, &[0x80], 1), [0x80, 0x80, 0x80, 0xFF]);
+        // Most significant bit first, the line padded to a whole byte.
+        assert_eq!(
+            convert_line(PixelFormat::R1, &[0b1000_0000], 2),
+            [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0xFF],
+        );
+    }
+
+    #[test]
+    fn rect_is_placed_in_frame() {
+        let mut frame = vec![0; 4 * 3 * 4];
+        let src = [0xAA; 2 * 2 * 4];
+        PixelFormat::Xrgb8888.convert(&src, Rect { x: 1, y: 1, width: 2, height: 2 }, &mut frame, 4);
+        let covered: Vec<bool> = frame.chunks_exact(4).map(|pixel| pixel[0] == 0xAA).collect();
+        assert_eq!(covered, [
+            false, false, false, false,
+            false, true,  true,  false,
+            false, true,  true,  false,
+        ]);
+    }
+}
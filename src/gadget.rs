@@ -1,13 +1,16 @@
-use crate::{AppEvent, hid};
+use crate::{AppEvent, gud, hid};
+use crate::backlight::Backlight;
 use crate::hid::SerializedDescriptor;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read};
 use std::path::{Path, PathBuf};
 use std::process::{Child, exit};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use anyhow::Context;
 use ipc_channel::ipc;
-use ipc_channel::ipc::{IpcError, IpcOneShotServer, IpcReceiver, IpcSender};
+use ipc_channel::ipc::{IpcError, IpcOneShotServer, IpcReceiver, IpcSender, TryRecvError};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info};
 use usb_gadget::function::hid::Hid;
@@ -15,7 +18,10 @@ use usb_gadget::{default_udc, remove_all, Class, Config, Gadget, Strings, RegGad
 use usb_gadget::function::custom::Custom;
 use winit::event_loop::EventLoopProxy;
 
-pub fn run(channel_name: String) -> anyhow::Result<()> {
+/// How often the panel backlight is checked for changes made on the device.
+const BACKLIGHT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn run(channel_name: String, sysfs_root: &Path) -> anyhow::Result<()> {
     let uid: u32 = std::env::var("PKEXEC_UID")
         .context("failed to get PKEXEC_UID")?
         .parse()
@@ -43,8 +49,21 @@ pub fn run(channel_name: String) -> anyhow::Result<()> {
     builder.protocol = 2;
     builder.report_len = 5;
     builder.report_desc = hid::MouseReport::desc().to_vec();
+    // Without an OUT endpoint f_hid hands SET_REPORT requests to the device's read queue, rather
+    // than stalling them, which is how the host sets the Resolution Multiplier feature report.
+    builder.no_out_endpoint = true;
     let (mouse_hid, mouse_handle) = builder.build();
 
+    let mut builder = Hid::builder();
+    builder.report_len = hid::TouchscreenReport::LEN as _;
+    builder.report_desc = hid::TouchscreenReport::desc().to_vec();
+    let (touch_hid, touch_handle) = builder.build();
+
+    let mut builder = Hid::builder();
+    builder.report_len = hid::PenReport::LEN as _;
+    builder.report_desc = hid::PenReport::desc().to_vec();
+    let (pen_hid, pen_handle) = builder.build();
+
     let mut builder = Custom::builder();
     builder.ffs_no_init = true;
     builder.ffs_uid = Some(uid);
@@ -52,18 +71,33 @@ pub fn run(channel_name: String) -> anyhow::Result<()> {
 
     let reg = Gadget::new(
         Class::interface_specific(),
-        gud_gadget::OPENMOKO_GUD_ID,
+        gud::OPENMOKO_GUD_ID,
         Strings::new("usb-kvm", "usb-kvm", "123"),
     )
         .with_config(
             Config::new("usb-kvm")
                 .with_function(kb_handle)
                 .with_function(mouse_handle)
+                .with_function(touch_handle)
+                .with_function(pen_handle)
                 .with_function(gud_handle)
         )
         .register()
         .context("failed to register gadget")?;
 
+    let backlight = find_backlight(sysfs_root);
+    // Sent before binding so the brightness is known by the time the host asks for it.
+    if let Some(backlight) = &backlight {
+        local_send.send(GadgetEvent::Backlight(backlight.lock().unwrap().percent()))?;
+        let backlight = backlight.clone();
+        let local_send = local_send.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = watch_backlight(backlight, local_send) {
+                error!("watching panel backlight failed: {}", err);
+            }
+        });
+    }
+
     local_send.send(GadgetEvent::Registered(gud.ffs_dir()?.to_str().unwrap().to_string()))?;
 
     let reg = Arc::new(Mutex::new(Some(reg)));
@@ -100,22 +134,143 @@ pub fn run(channel_name: String) -> anyhow::Result<()> {
             major, minor
         ))
     };
+    let touch_dev = {
+        let (major, minor) = touch_hid.device().unwrap();
+        PathBuf::from(format!(
+            "/dev/char/{}:{}",
+            major, minor
+        ))
+    };
+    let pen_dev = {
+        let (major, minor) = pen_hid.device().unwrap();
+        PathBuf::from(format!(
+            "/dev/char/{}:{}",
+            major, minor
+        ))
+    };
+
+    {
+        let mouse_dev = mouse_dev.clone();
+        let local_send = local_send.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = read_mouse_features(&mouse_dev, local_send) {
+                error!("reading mouse feature reports failed: {}", err);
+            }
+        });
+    }
+
+    let mut jiggle_interval = None;
+    let mut next_jiggle = Instant::now();
+    // Buttons held in the last mouse report, which the jiggler's reports mustn't release.
+    let mut mouse_buttons = 0;
 
     loop {
-        match local_receive.recv()? {
-            IpcCommand::MouseReport(report) => std::fs::write(&mouse_dev, report)?,
+        let command = match jiggle_interval {
+            Some(interval) => match local_receive.try_recv_timeout(next_jiggle.saturating_duration_since(Instant::now())) {
+                Ok(command) => command,
+                Err(TryRecvError::Empty) => {
+                    jiggle(&mouse_dev, mouse_buttons)?;
+                    next_jiggle = Instant::now() + interval;
+                    continue;
+                }
+                // The main process is gone, nothing left to forward.
+                Err(TryRecvError::IpcError(IpcError::Disconnected)) => break,
+                Err(TryRecvError::IpcError(err)) => return Err(err.into()),
+            },
+            None => match local_receive.recv() {
+                Ok(command) => command,
+                Err(IpcError::Disconnected) => break,
+                Err(err) => return Err(err.into()),
+            },
+        };
+
+        // Real input means someone is at the keyboard, the host will stay awake by itself now.
+        if jiggle_interval.is_some() && command.is_input() {
+            info!("input arrived, stopping mouse jiggler");
+            jiggle_interval = None;
+            local_send.send(GadgetEvent::JigglerStopped)?;
+        }
+
+        match command {
+            IpcCommand::MouseReport(report) => {
+                mouse_buttons = report[0];
+                std::fs::write(&mouse_dev, report)?
+            }
             IpcCommand::KeyboardReport(report) => std::fs::write(&kb_dev, report)?,
+            IpcCommand::TouchReport(report) => std::fs::write(&touch_dev, report)?,
+            IpcCommand::PenReport(report) => std::fs::write(&pen_dev, report)?,
+            IpcCommand::SetJiggler(interval) => {
+                info!("mouse jiggler interval set to {:?}", interval);
+                jiggle_interval = interval;
+                next_jiggle = Instant::now();
+            }
+            IpcCommand::SetBacklight(percent) => match &backlight {
+                Some(backlight) => {
+                    if let Err(err) = backlight.lock().unwrap().set_percent(percent) {
+                        error!("failed to set panel backlight to {}%: {:#}", percent, err);
+                    }
+                }
+                None => debug!("no panel backlight to set to {}%", percent),
+            },
             v => panic!("unexpected IPC command {:?}", v)
         }
     }
 
-    //
-    //
-    // //
     cleanup(reg.clone());
     Ok(())
 }
 
+/// Nudges the pointer one unit right and straight back, enough to count as activity on the host
+/// without actually moving anything. The buttons are reported as they were last sent.
+fn jiggle(mouse_dev: &Path, buttons: u8) -> anyhow::Result<()> {
+    std::fs::write(mouse_dev, [buttons, 1, 0, 0, 0])?;
+    std::fs::write(mouse_dev, [buttons, -1i8 as u8, 0, 0, 0])?;
+    Ok(())
+}
+
+/// The mouse has no output reports, so anything the host sets on the hidg device is the feature
+/// report that toggles the wheel/pan Resolution Multipliers.
+fn read_mouse_features(mouse_dev: &Path, events: IpcSender<GadgetEvent>) -> anyhow::Result<()> {
+    let mut dev = File::open(mouse_dev).context("failed to open mouse device")?;
+    let mut buf = [0; 8];
+    loop {
+        match dev.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {
+                let features = hid::MouseFeatureReport::parse(buf[0]);
+                debug!("mouse feature report set: {:?}", features);
+                events.send(GadgetEvent::MouseFeatures(features))?;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The panel backlight under the given sysfs root, shared with the thread watching it. A device
+/// without one, or one that can't be read, just doesn't offer the host a brightness setting.
+pub fn find_backlight(sysfs_root: &Path) -> Option<Arc<Mutex<Backlight>>> {
+    match Backlight::find(sysfs_root) {
+        Ok(backlight) => backlight.map(|backlight| Arc::new(Mutex::new(backlight))),
+        Err(err) => {
+            error!("failed to find panel backlight: {}", err);
+            None
+        }
+    }
+}
+
+/// Reports brightness changes made on the device, e.g. with its own brightness keys, so they're
+/// offered to the host the next time it reads the connector properties.
+fn watch_backlight(backlight: Arc<Mutex<Backlight>>, events: IpcSender<GadgetEvent>) -> anyhow::Result<()> {
+    loop {
+        std::thread::sleep(BACKLIGHT_POLL_INTERVAL);
+        if let Some(percent) = backlight.lock().unwrap().poll()? {
+            debug!("panel backlight changed to {}%", percent);
+            events.send(GadgetEvent::Backlight(percent))?;
+        }
+    }
+}
+
 fn cleanup(reg: Arc<Mutex<Option<RegGadget>>>) {
     if let Some(reg) = reg.lock().unwrap().take() {
         reg.remove().unwrap();
@@ -124,7 +279,8 @@ fn cleanup(reg: Arc<Mutex<Option<RegGadget>>>) {
 }
 
 pub struct GadgetProcess {
-    process: Child,
+    // Only held on to, the process ends by itself once its IPC channel disconnects.
+    _process: Child,
     sender: IpcSender<IpcCommand>,
 }
 
@@ -133,6 +289,25 @@ pub enum IpcCommand {
     Bind,
     KeyboardReport(Vec<u8>),
     MouseReport([u8; 5]),
+    TouchReport(Vec<u8>),
+    PenReport(Vec<u8>),
+    /// Starts the mouse jiggler with the given interval, or stops it.
+    SetJiggler(Option<Duration>),
+    /// Sets the panel backlight brightness, as a percentage.
+    SetBacklight(u8),
+}
+
+impl IpcCommand {
+    /// Whether this command carries input from the user.
+    fn is_input(&self) -> bool {
+        matches!(
+            self,
+            IpcCommand::KeyboardReport(_)
+                | IpcCommand::MouseReport(_)
+                | IpcCommand::TouchReport(_)
+                | IpcCommand::PenReport(_)
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -140,6 +315,11 @@ pub enum GadgetEvent {
     Disconnected,
     Registered(String),
     Bound,
+    MouseFeatures(hid::MouseFeatureReport),
+    /// The mouse jiggler stopped itself because real input arrived.
+    JigglerStopped,
+    /// The panel backlight brightness, as a percentage. Sent at startup and whenever it changes.
+    Backlight(u8),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -150,7 +330,7 @@ struct IpcHandshake {
 
 /// Start the privileged gadget process, setup IPC channel.
 /// Events from the gadget process will be pumped into the main winit event loop.
-pub fn spawn(events: EventLoopProxy<AppEvent>) -> anyhow::Result<GadgetProcess> {
+pub fn spawn(events: EventLoopProxy<AppEvent>, sysfs_root: &Path) -> anyhow::Result<GadgetProcess> {
     let (ipc, channel_name) = IpcOneShotServer::<IpcHandshake>::new()?;
     let arg0 = std::env::args().next().unwrap();
 
@@ -158,14 +338,9 @@ pub fn spawn(events: EventLoopProxy<AppEvent>) -> anyhow::Result<GadgetProcess>
 
     let process = std::process::Command::new("pkexec")
         .arg(&arg0)
-        .arg("--gadget")
-        .arg(channel_name)
+        .args(process_args(channel_name, sysfs_root))
         .spawn().context(format!("failed to start process '{}'", arg0))?;
 
-    loop {
-        process.
-    }
-
     let (_, IpcHandshake { receiver, sender }) = ipc.accept().context("ipc handshake")?;
 
     std::thread::spawn(move || {
@@ -186,13 +361,23 @@ pub fn spawn(events: EventLoopProxy<AppEvent>) -> anyhow::Result<GadgetProcess>
     });
 
     Ok(GadgetProcess {
-        process,
+        _process: process,
         sender,
     })
 }
 
+/// The arguments the gadget process is started with, after the executable.
+pub fn process_args(channel_name: String, sysfs_root: &Path) -> Vec<OsString> {
+    vec![
+        "--gadget".into(),
+        channel_name.into(),
+        "--sysfs-root".into(),
+        sysfs_root.into(),
+    ]
+}
+
 impl GadgetProcess {
     pub fn send(&self, msg: IpcCommand) -> anyhow::Result<()> {
-        Ok(self.sender.send(msg).context("failed to send IPC message to gadget process")?)
+        self.sender.send(msg).context("failed to send IPC message to gadget process")
     }
 }
\ No newline at end of file
@@ -0,0 +1,546 @@
+//! The device side of the Generic USB Display protocol, see include/drm/gud.h in the kernel.
+//!
+//! Requests the host only needs a fixed answer to are handled here, the rest are handed to the
+//! caller as [`Event`]s.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use bytes::BytesMut;
+use tracing::{debug, error};
+use usb_gadget::function::custom::{self, CtrlReceiver, CtrlSender, Endpoint, EndpointDirection, EndpointReceiver};
+use usb_gadget::Id;
+
+/// The USB ID assigned to GUD devices, which the host driver binds to.
+pub const OPENMOKO_GUD_ID: Id = Id::new(0x1d50, 0x614d);
+
+const GUD_DISPLAY_MAGIC: u32 = 0x1d50614d;
+const GUD_DISPLAY_VERSION: u8 = 1;
+
+/// Large updates are split by the host into transfers of at most this many bytes.
+const MAX_BUFFER_SIZE: u32 = 4 * 1024 * 1024;
+/// Bulk transfers are read in chunks of this size, a multiple of any bulk max packet size.
+const MAX_RECV_LEN: usize = 64 * 1024;
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub const GUD_COMPRESSION_LZ4: u8 = 1 << 0;
+
+pub const GUD_PIXEL_FORMAT_R1: u8 = 0x01;
+pub const GUD_PIXEL_FORMAT_R8: u8 = 0x08;
+pub const GUD_PIXEL_FORMAT_RGB565: u8 = 0x40;
+pub const GUD_PIXEL_FORMAT_RGB888: u8 = 0x50;
+pub const GUD_PIXEL_FORMAT_XRGB8888: u8 = 0x80;
+
+pub const GUD_PROPERTY_BACKLIGHT_BRIGHTNESS: u16 = 12;
+pub const GUD_PROPERTY_ROTATION: u16 = 50;
+
+// Values of the rotation property, counter-clockwise like DRM's.
+pub const GUD_ROTATION_0: u64 = 1 << 0;
+pub const GUD_ROTATION_90: u64 = 1 << 1;
+pub const GUD_ROTATION_180: u64 = 1 << 2;
+pub const GUD_ROTATION_270: u64 = 1 << 3;
+
+pub const GUD_DISPLAY_MODE_FLAG_PHSYNC: u32 = 1 << 0;
+pub const GUD_DISPLAY_MODE_FLAG_NVSYNC: u32 = 1 << 3;
+pub const GUD_DISPLAY_MODE_FLAG_PREFERRED: u32 = 1 << 10;
+
+const GUD_CONNECTOR_TYPE_PANEL: u8 = 0;
+const GUD_CONNECTOR_FLAGS_POLL_STATUS: u32 = 1 << 0;
+const GUD_CONNECTOR_STATUS_DISCONNECTED: u8 = 0x00;
+const GUD_CONNECTOR_STATUS_CONNECTED: u8 = 0x01;
+const GUD_CONNECTOR_STATUS_CHANGED: u8 = 1 << 7;
+
+const GUD_STATUS_OK: u8 = 0x00;
+const GUD_STATUS_REQUEST_NOT_SUPPORTED: u8 = 0x02;
+const GUD_STATUS_PROTOCOL_ERROR: u8 = 0x03;
+const GUD_STATUS_INVALID_PARAMETER: u8 = 0x04;
+
+const GUD_REQ_GET_STATUS: u8 = 0x00;
+const GUD_REQ_GET_DESCRIPTOR: u8 = 0x01;
+const GUD_REQ_GET_FORMATS: u8 = 0x40;
+const GUD_REQ_GET_PROPERTIES: u8 = 0x41;
+const GUD_REQ_GET_CONNECTORS: u8 = 0x50;
+const GUD_REQ_GET_CONNECTOR_PROPERTIES: u8 = 0x51;
+const GUD_REQ_SET_CONNECTOR_FORCE_DETECT: u8 = 0x53;
+const GUD_REQ_GET_CONNECTOR_STATUS: u8 = 0x54;
+const GUD_REQ_GET_CONNECTOR_MODES: u8 = 0x55;
+const GUD_REQ_GET_CONNECTOR_EDID: u8 = 0x56;
+const GUD_REQ_SET_BUFFER: u8 = 0x60;
+const GUD_REQ_SET_STATE_CHECK: u8 = 0x61;
+const GUD_REQ_SET_STATE_COMMIT: u8 = 0x62;
+const GUD_REQ_SET_CONTROLLER_ENABLE: u8 = 0x63;
+const GUD_REQ_SET_DISPLAY_ENABLE: u8 = 0x64;
+
+const DISPLAY_MODE_LEN: usize = 24;
+const PROPERTY_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayMode {
+    /// Pixel clock in kHz.
+    pub clock: u32,
+    pub hdisplay: u16,
+    pub hsync_start: u16,
+    pub hsync_end: u16,
+    pub htotal: u16,
+    pub vdisplay: u16,
+    pub vsync_start: u16,
+    pub vsync_end: u16,
+    pub vtotal: u16,
+    pub flags: u32,
+}
+
+impl DisplayMode {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.clock.to_le_bytes());
+        for value in [
+            self.hdisplay,
+            self.hsync_start,
+            self.hsync_end,
+            self.htotal,
+            self.vdisplay,
+            self.vsync_start,
+            self.vsync_end,
+            self.vtotal,
+        ] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&self.flags.to_le_bytes());
+    }
+
+    fn read(reader: &mut Reader) -> anyhow::Result<Self> {
+        Ok(Self {
+            clock: reader.u32()?,
+            hdisplay: reader.u16()?,
+            hsync_start: reader.u16()?,
+            hsync_end: reader.u16()?,
+            htotal: reader.u16()?,
+            vdisplay: reader.u16()?,
+            vsync_start: reader.u16()?,
+            vsync_end: reader.u16()?,
+            vtotal: reader.u16()?,
+            flags: reader.u32()?,
+        })
+    }
+}
+
+/// The display state the host committed: the mode, the pixel format frames will be sent in, and
+/// the value of every connector property.
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub mode: DisplayMode,
+    pub format: u8,
+    pub properties: Vec<(u16, u64)>,
+}
+
+impl State {
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader(data);
+        let mode = DisplayMode::read(&mut reader)?;
+        let format = reader.u8()?;
+        let connector = reader.u8()?;
+        if connector != 0 {
+            bail!("state for unknown connector {}", connector);
+        }
+        if reader.0.len() % PROPERTY_LEN != 0 {
+            bail!("state has a truncated property");
+        }
+        let mut properties = Vec::new();
+        while !reader.0.is_empty() {
+            properties.push((reader.u16()?, reader.u64()?));
+        }
+        Ok(Self { mode, format, properties })
+    }
+}
+
+/// Where a buffer sent by the host goes in the framebuffer, and how it is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferInfo {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Length of the pixel data once decompressed.
+    pub length: u32,
+    pub compression: u8,
+    /// Length of the bulk transfer, if compressed.
+    pub compressed_length: u32,
+}
+
+impl BufferInfo {
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader(data);
+        Ok(Self {
+            x: reader.u32()?,
+            y: reader.u32()?,
+            width: reader.u32()?,
+            height: reader.u32()?,
+            length: reader.u32()?,
+            compression: reader.u8()?,
+            compressed_length: reader.u32()?,
+        })
+    }
+
+    /// Length of the bulk transfer carrying the pixel data.
+    pub fn transfer_len(&self) -> usize {
+        match self.compression {
+            0 => self.length as usize,
+            _ => self.compressed_length as usize,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Event<'a> {
+    GetDescriptor(Request<'a>),
+    GetPixelFormats(Request<'a>),
+    GetDisplayModes(Request<'a>),
+    GetProperties(Request<'a>),
+    GetEdid(Request<'a>),
+    GetConnectorProperties(Request<'a>),
+    GetConnectorStatus(Request<'a>),
+    SetState(State),
+    /// The pixel data follows on the bulk endpoint, see [`PixelDataEndpoint::recv_raw`].
+    Buffer(BufferInfo),
+}
+
+/// A request the host expects an answer to. Dropping it stalls the request.
+#[derive(Debug)]
+pub struct Request<'a>(CtrlSender<'a>);
+
+impl Request<'_> {
+    pub fn send_descriptor(self, min_width: u32, min_height: u32, max_width: u32, max_height: u32, compression: u8) -> anyhow::Result<()> {
+        self.send(&descriptor(min_width, min_height, max_width, max_height, compression))
+    }
+
+    pub fn send_pixel_formats(self, formats: &[u8]) -> anyhow::Result<()> {
+        self.send(formats)
+    }
+
+    pub fn send_modes(self, modes: &[DisplayMode]) -> anyhow::Result<()> {
+        let mut data = Vec::with_capacity(modes.len() * DISPLAY_MODE_LEN);
+        for mode in modes {
+            mode.write(&mut data);
+        }
+        self.send(&data)
+    }
+
+    pub fn send_edid(self, edid: &[u8]) -> anyhow::Result<()> {
+        self.send(edid)
+    }
+
+    pub fn send_properties(self, properties: &[(u16, u64)]) -> anyhow::Result<()> {
+        self.send(&properties_bytes(properties))
+    }
+
+    /// A change makes the host read the modes and EDID again.
+    pub fn send_status(self, connected: bool, changed: bool) -> anyhow::Result<()> {
+        let mut status = match connected {
+            true => GUD_CONNECTOR_STATUS_CONNECTED,
+            false => GUD_CONNECTOR_STATUS_DISCONNECTED,
+        };
+        if changed {
+            status |= GUD_CONNECTOR_STATUS_CHANGED;
+        }
+        self.send(&[status])
+    }
+
+    fn send(self, data: &[u8]) -> anyhow::Result<()> {
+        // The host asks for as much as it can take, which is often more than there is.
+        let len = data.len().min(self.0.len());
+        self.0.send(&data[..len])?;
+        Ok(())
+    }
+}
+
+/// Turns control requests on the GUD function into [`Event`]s.
+#[derive(Default)]
+pub struct Gud {
+    /// Answer to GET_STATUS, which the host asks for after a request was stalled.
+    status: u8,
+    /// Checked by the host before it commits it.
+    checked: Option<State>,
+}
+
+impl Gud {
+    pub fn event<'a>(&mut self, event: custom::Event<'a>) -> anyhow::Result<Option<Event<'a>>> {
+        match event {
+            custom::Event::SetupDeviceToHost(sender) => self.device_to_host(sender),
+            custom::Event::SetupHostToDevice(receiver) => self.host_to_device(receiver),
+            _ => Ok(None),
+        }
+    }
+
+    fn device_to_host<'a>(&mut self, sender: CtrlSender<'a>) -> anyhow::Result<Option<Event<'a>>> {
+        let ctrl_req = sender.ctrl_req().clone();
+        if ctrl_req.request == GUD_REQ_GET_STATUS {
+            sender.send(&[self.status])?;
+            return Ok(None);
+        }
+        self.status = GUD_STATUS_OK;
+        let connector_request = matches!(
+            ctrl_req.request,
+            GUD_REQ_GET_CONNECTOR_PROPERTIES
+                | GUD_REQ_GET_CONNECTOR_STATUS
+                | GUD_REQ_GET_CONNECTOR_MODES
+                | GUD_REQ_GET_CONNECTOR_EDID
+        );
+        if connector_request && ctrl_req.value != 0 {
+            return self.stall_send(sender, GUD_STATUS_INVALID_PARAMETER);
+        }
+        let request = Request(sender);
+        Ok(Some(match ctrl_req.request {
+            GUD_REQ_GET_DESCRIPTOR => Event::GetDescriptor(request),
+            GUD_REQ_GET_FORMATS => Event::GetPixelFormats(request),
+            GUD_REQ_GET_PROPERTIES => Event::GetProperties(request),
+            GUD_REQ_GET_CONNECTORS => {
+                let mut descriptor = vec![GUD_CONNECTOR_TYPE_PANEL];
+                descriptor.extend_from_slice(&GUD_CONNECTOR_FLAGS_POLL_STATUS.to_le_bytes());
+                request.send(&descriptor)?;
+                return Ok(None);
+            }
+            GUD_REQ_GET_CONNECTOR_PROPERTIES => Event::GetConnectorProperties(request),
+            GUD_REQ_GET_CONNECTOR_STATUS => Event::GetConnectorStatus(request),
+            GUD_REQ_GET_CONNECTOR_MODES => Event::GetDisplayModes(request),
+            GUD_REQ_GET_CONNECTOR_EDID => Event::GetEdid(request),
+            other => {
+                debug!("unsupported GUD request {:#x}", other);
+                return self.stall_send(request.0, GUD_STATUS_REQUEST_NOT_SUPPORTED);
+            }
+        }))
+    }
+
+    fn host_to_device<'a>(&mut self, receiver: CtrlReceiver<'a>) -> anyhow::Result<Option<Event<'a>>> {
+        let request = receiver.ctrl_req().request;
+        self.status = GUD_STATUS_OK;
+        match request {
+            GUD_REQ_SET_BUFFER => {
+                let data = receiver.recv_all()?;
+                match BufferInfo::parse(&data) {
+                    Ok(info) => Ok(Some(Event::Buffer(info))),
+                    Err(err) => self.protocol_error(err),
+                }
+            }
+            GUD_REQ_SET_STATE_CHECK => {
+                let data = receiver.recv_all()?;
+                match State::parse(&data) {
+                    Ok(state) => {
+                        self.checked = Some(state);
+                        Ok(None)
+                    }
+                    Err(err) => self.protocol_error(err),
+                }
+            }
+            // Committing applies whatever state was checked last, the host only commits states
+            // that passed the check.
+            GUD_REQ_SET_STATE_COMMIT => {
+                receiver.recv(&mut [])?;
+                match self.checked.take() {
+                    Some(state) => Ok(Some(Event::SetState(state))),
+                    None => {
+                        self.status = GUD_STATUS_PROTOCOL_ERROR;
+                        Ok(None)
+                    }
+                }
+            }
+            // The window keeps showing the last frame while the host has the display off.
+            GUD_REQ_SET_CONNECTOR_FORCE_DETECT | GUD_REQ_SET_CONTROLLER_ENABLE | GUD_REQ_SET_DISPLAY_ENABLE => {
+                receiver.recv_all()?;
+                Ok(None)
+            }
+            other => {
+                debug!("unsupported GUD request {:#x}", other);
+                self.status = GUD_STATUS_REQUEST_NOT_SUPPORTED;
+                receiver.halt()?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn stall_send<'a>(&mut self, sender: CtrlSender<'a>, status: u8) -> anyhow::Result<Option<Event<'a>>> {
+        self.status = status;
+        sender.halt()?;
+        Ok(None)
+    }
+
+    /// The data of a host to device request has been received by now, so the request can't be
+    /// stalled anymore. The host only finds out if it asks for the status.
+    fn protocol_error<'a>(&mut self, err: anyhow::Error) -> anyhow::Result<Option<Event<'a>>> {
+        error!("malformed GUD request: {}", err);
+        self.status = GUD_STATUS_PROTOCOL_ERROR;
+        Ok(None)
+    }
+}
+
+/// The bulk endpoint pixel data arrives on.
+pub struct PixelDataEndpoint(EndpointReceiver);
+
+impl PixelDataEndpoint {
+    pub fn new() -> (Self, Endpoint) {
+        let (receiver, direction) = EndpointDirection::host_to_device();
+        (Self(receiver), Endpoint::bulk(direction))
+    }
+
+    /// Receives the bulk transfer following a [`Event::Buffer`] into `buf`, which has to be
+    /// exactly [`BufferInfo::transfer_len`] long.
+    pub fn recv_raw(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        let mut received = 0;
+        while received < buf.len() {
+            let len = (buf.len() - received).min(MAX_RECV_LEN);
+            let data = self
+                .0
+                .recv_and_fetch_timeout(BytesMut::with_capacity(len), RECV_TIMEOUT)
+                .context("failed to receive pixel data")?;
+            buf[received..received + data.len()].copy_from_slice(&data);
+            received += data.len();
+            // A short packet ends the transfer.
+            if data.len() < len {
+                bail!("transfer ended after {} of {} bytes", received, buf.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decompresses an LZ4 block into `dst`, which has to be exactly [`BufferInfo::length`] long.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> anyhow::Result<()> {
+    let len = lz4::block::decompress_to_buffer(src, Some(dst.len() as i32), dst).context("failed to decompress buffer")?;
+    if len != dst.len() {
+        bail!("buffer decompressed to {} of {} bytes", len, dst.len());
+    }
+    Ok(())
+}
+
+fn descriptor(min_width: u32, min_height: u32, max_width: u32, max_height: u32, compression: u8) -> Vec<u8> {
+    let mut data = GUD_DISPLAY_MAGIC.to_le_bytes().to_vec();
+    data.push(GUD_DISPLAY_VERSION);
+    // No flags: the host doesn't need to ask for the status after every request, and only sends
+    // what changed.
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.push(compression);
+    for value in [MAX_BUFFER_SIZE, min_width, max_width, min_height, max_height] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data
+}
+
+fn properties_bytes(properties: &[(u16, u64)]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(properties.len() * PROPERTY_LEN);
+    for &(property, value) in properties {
+        data.extend_from_slice(&property.to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data
+}
+
+/// Reads little endian fields off the front of a request.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        if self.0.len() < N {
+            bail!("request is too short");
+        }
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(field.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(u8::from_le_bytes(self.take()?))
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODE: DisplayMode = DisplayMode {
+        clock: 138_500,
+        hdisplay: 1920,
+        hsync_start: 1968,
+        hsync_end: 2000,
+        htotal: 2080,
+        vdisplay: 1080,
+        vsync_start: 1083,
+        vsync_end: 1088,
+        vtotal: 1111,
+        flags: GUD_DISPLAY_MODE_FLAG_PHSYNC | GUD_DISPLAY_MODE_FLAG_NVSYNC,
+    };
+
+    #[test]
+    fn descriptor_layout() {
+        let data = descriptor(240, 240, 4096, 4096, GUD_COMPRESSION_LZ4);
+        assert_eq!(data.len(), 30);
+        assert_eq!(data[0..4], [0x4d, 0x61, 0x50, 0x1d]);
+        assert_eq!(data[4], GUD_DISPLAY_VERSION);
+        assert_eq!(data[9], GUD_COMPRESSION_LZ4);
+        // min_width, max_width, min_height, max_height
+        assert_eq!(data[14..18], 240u32.to_le_bytes());
+        assert_eq!(data[18..22], 4096u32.to_le_bytes());
+        assert_eq!(data[22..26], 240u32.to_le_bytes());
+    }
+
+    #[test]
+    fn mode_round_trips() {
+        let mut data = Vec::new();
+        MODE.write(&mut data);
+        assert_eq!(data.len(), DISPLAY_MODE_LEN);
+        assert_eq!(DisplayMode::read(&mut Reader(&data)).unwrap(), MODE);
+    }
+
+    #[test]
+    fn state_parses_properties() {
+        let mut data = Vec::new();
+        MODE.write(&mut data);
+        data.extend_from_slice(&[GUD_PIXEL_FORMAT_RGB565, 0]);
+        data.extend_from_slice(&properties_bytes(&[(GUD_PROPERTY_BACKLIGHT_BRIGHTNESS, 42)]));
+        let state = State::parse(&data).unwrap();
+        assert_eq!(state.mode, MODE);
+        assert_eq!(state.format, GUD_PIXEL_FORMAT_RGB565);
+        assert_eq!(state.properties, [(GUD_PROPERTY_BACKLIGHT_BRIGHTNESS, 42)]);
+
+        assert!(State::parse(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn buffer_info_parses() {
+        let mut data = Vec::new();
+        for value in [16u32, 32, 100, 50, 20_000] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.push(GUD_COMPRESSION_LZ4);
+        data.extend_from_slice(&1234u32.to_le_bytes());
+        let info = BufferInfo::parse(&data).unwrap();
+        assert_eq!((info.x, info.y, info.width, info.height), (16, 32, 100, 50));
+        assert_eq!((info.length, info.compression, info.compressed_length), (20_000, GUD_COMPRESSION_LZ4, 1234));
+        assert_eq!(info.transfer_len(), 1234);
+        assert_eq!(BufferInfo { compression: 0, ..info }.transfer_len(), 20_000);
+    }
+
+    #[test]
+    fn lz4_round_trips() {
+        let pixels: Vec<u8> = (0..4096u32).flat_map(|i| [(i / 64) as u8, 0x20, 0x30, 0x00]).collect();
+        let compressed = lz4::block::compress(&pixels, None, false).unwrap();
+        assert!(compressed.len() < pixels.len());
+
+        let mut decompressed = vec![0; pixels.len()];
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, pixels);
+
+        // The host says how long the buffer is, anything else is an error.
+        assert!(decompress(&compressed, &mut vec![0; pixels.len() - 1]).is_err());
+        assert!(decompress(&compressed[..compressed.len() / 2], &mut decompressed).is_err());
+    }
+}
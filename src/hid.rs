@@ -1,9 +1,12 @@
 use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::sync::OnceLock;
 use usbd_hid_macros::gen_hid_descriptor;
 
 pub trait SerializedDescriptor {
     fn desc() -> &'static [u8];
 }
+// Implemented by the reports `gen_hid_descriptor` generates, nothing here needs it.
+#[allow(dead_code)]
 pub trait AsInputReport: Serialize {}
 
 /// KeyboardReport describes a report and its companion descriptor that can be
@@ -35,31 +38,11 @@ pub struct KeyboardReport {
 
 /// MouseReport describes a report and its companion descriptor than can be used
 /// to send mouse movements and button presses to a host.
-#[gen_hid_descriptor(
-    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
-        (collection = PHYSICAL, usage = POINTER) = {
-            (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = BUTTON_8) = {
-                #[packed_bits 8] #[item_settings data,variable,absolute] buttons=input;
-            };
-            (usage_page = GENERIC_DESKTOP,) = {
-                (usage = X,) = {
-                    #[item_settings data,variable,relative] x=input;
-                };
-                (usage = Y,) = {
-                    #[item_settings data,variable,relative] y=input;
-                };
-                (usage = WHEEL,) = {
-                    #[item_settings data,variable,relative] wheel=input;
-                };
-            };
-            (usage_page = CONSUMER,) = {
-                (usage = AC_PAN,) = {
-                    #[item_settings data,variable,relative] pan=input;
-                };
-            };
-        };
-    }
-)]
+///
+/// The descriptor is written out by hand since the wheel and pan usages each need a Resolution
+/// Multiplier feature with a physical range, which `gen_hid_descriptor` can't express. Hosts that
+/// understand it (Windows, Linux) switch the wheel and pan to units of 1/[`MOUSE_HIRES_MULTIPLIER`]
+/// of a notch by setting the feature report, see [`MouseFeatureReport`].
 #[allow(dead_code)]
 pub struct MouseReport {
     pub buttons: u8,
@@ -68,3 +51,366 @@ pub struct MouseReport {
     pub wheel: i8, // Scroll down (negative) or up (positive) this many units
     pub pan: i8,   // Scroll left (negative) or right (positive) this many units
 }
+
+/// How many hi-res wheel/pan units make up a single notch once the host has enabled the
+/// Resolution Multiplier.
+pub const MOUSE_HIRES_MULTIPLIER: u8 = 8;
+
+impl SerializedDescriptor for MouseReport {
+    fn desc() -> &'static [u8] {
+        &[
+            0x05, 0x01, // Usage Page (Generic Desktop)
+            0x09, 0x02, // Usage (Mouse)
+            0xA1, 0x01, // Collection (Application)
+            0x09, 0x01, //   Usage (Pointer)
+            0xA1, 0x00, //   Collection (Physical)
+            0x05, 0x09, //     Usage Page (Button)
+            0x19, 0x01, //     Usage Minimum (Button 1)
+            0x29, 0x08, //     Usage Maximum (Button 8)
+            0x15, 0x00, //     Logical Minimum (0)
+            0x25, 0x01, //     Logical Maximum (1)
+            0x75, 0x01, //     Report Size (1)
+            0x95, 0x08, //     Report Count (8)
+            0x81, 0x02, //     Input (Data, Variable, Absolute)
+            0x05, 0x01, //     Usage Page (Generic Desktop)
+            0x09, 0x30, //     Usage (X)
+            0x09, 0x31, //     Usage (Y)
+            0x15, 0x81, //     Logical Minimum (-127)
+            0x25, 0x7F, //     Logical Maximum (127)
+            0x75, 0x08, //     Report Size (8)
+            0x95, 0x02, //     Report Count (2)
+            0x81, 0x06, //     Input (Data, Variable, Relative)
+            0xA1, 0x02, //     Collection (Logical)
+            0x09, 0x48, //       Usage (Resolution Multiplier)
+            0x15, 0x00, //       Logical Minimum (0)
+            0x25, 0x01, //       Logical Maximum (1)
+            0x35, 0x01, //       Physical Minimum (1)
+            0x45, MOUSE_HIRES_MULTIPLIER, // Physical Maximum
+            0x75, 0x02, //       Report Size (2)
+            0x95, 0x01, //       Report Count (1)
+            0xB1, 0x02, //       Feature (Data, Variable, Absolute)
+            0x35, 0x00, //       Physical Minimum (0)
+            0x45, 0x00, //       Physical Maximum (0)
+            0x09, 0x38, //       Usage (Wheel)
+            0x15, 0x81, //       Logical Minimum (-127)
+            0x25, 0x7F, //       Logical Maximum (127)
+            0x75, 0x08, //       Report Size (8)
+            0x81, 0x06, //       Input (Data, Variable, Relative)
+            0xC0,       //     End Collection
+            0xA1, 0x02, //     Collection (Logical)
+            0x09, 0x48, //       Usage (Resolution Multiplier)
+            0x15, 0x00, //       Logical Minimum (0)
+            0x25, 0x01, //       Logical Maximum (1)
+            0x35, 0x01, //       Physical Minimum (1)
+            0x45, MOUSE_HIRES_MULTIPLIER, // Physical Maximum
+            0x75, 0x02, //       Report Size (2)
+            0xB1, 0x02, //       Feature (Data, Variable, Absolute)
+            0x35, 0x00, //       Physical Minimum (0)
+            0x45, 0x00, //       Physical Maximum (0)
+            0x05, 0x0C, //       Usage Page (Consumer)
+            0x0A, 0x38, 0x02, // Usage (AC Pan)
+            0x15, 0x81, //       Logical Minimum (-127)
+            0x25, 0x7F, //       Logical Maximum (127)
+            0x75, 0x08, //       Report Size (8)
+            0x81, 0x06, //       Input (Data, Variable, Relative)
+            0xC0,       //     End Collection
+            0x75, 0x04, //     Report Size (4)
+            0xB1, 0x03, //     Feature (Constant) - pad the feature report out to a byte
+            0xC0,       //   End Collection
+            0xC0,       // End Collection
+        ]
+    }
+}
+
+impl Serialize for MouseReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_tuple(5)?;
+        s.serialize_element(&self.buttons)?;
+        s.serialize_element(&self.x)?;
+        s.serialize_element(&self.y)?;
+        s.serialize_element(&self.wheel)?;
+        s.serialize_element(&self.pan)?;
+        s.end()
+    }
+}
+
+impl AsInputReport for MouseReport {}
+
+/// MouseFeatureReport is the single byte the host sets to enable the Resolution Multipliers
+/// declared in the [`MouseReport`] descriptor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MouseFeatureReport {
+    pub wheel_hires: bool,
+    pub pan_hires: bool,
+}
+
+impl MouseFeatureReport {
+    pub fn parse(report: u8) -> Self {
+        Self {
+            wheel_hires: report & 0b0011 != 0,
+            pan_hires: report & 0b1100 != 0,
+        }
+    }
+}
+
+/// How many simultaneous contacts the touchscreen reports.
+pub const TOUCH_MAX_CONTACTS: usize = 5;
+/// Touch coordinates are scaled to 0..=TOUCH_LOGICAL_MAX across the display.
+pub const TOUCH_LOGICAL_MAX: u16 = 0x7FFF;
+/// Physical size the digitizers claim to cover, in tenths of a millimetre. Hosts insist on a
+/// physical size but map the digitizer onto the whole monitor regardless, and the mode the host
+/// picks changes anyway, so this is a nominal 15.6" 16:9 panel.
+const DIGITIZER_PHYSICAL_SIZE: (u16, u16) = (3442, 1936);
+
+/// TouchContact is a single finger slot within a [`TouchscreenReport`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TouchContact {
+    pub tip: bool,
+    pub id: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
+/// TouchscreenReport describes a multi-touch digitizer report (in parallel mode, all contacts in a
+/// single report) and its companion descriptor.
+#[derive(Debug, Default)]
+pub struct TouchscreenReport {
+    pub contacts: [TouchContact; TOUCH_MAX_CONTACTS],
+    pub count: u8,
+}
+
+impl TouchscreenReport {
+    pub const LEN: usize = TOUCH_MAX_CONTACTS * 6 + 2;
+}
+
+/// The X and Y fields of a digitizer, scaled to [`TOUCH_LOGICAL_MAX`] and spanning
+/// [`DIGITIZER_PHYSICAL_SIZE`]. The units are reset afterwards so they don't apply to later fields.
+fn digitizer_position() -> Vec<u8> {
+    let [max_lo, max_hi] = TOUCH_LOGICAL_MAX.to_le_bytes();
+    let [width_lo, width_hi] = DIGITIZER_PHYSICAL_SIZE.0.to_le_bytes();
+    let [height_lo, height_hi] = DIGITIZER_PHYSICAL_SIZE.1.to_le_bytes();
+    vec![
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x26, max_lo, max_hi, // Logical Maximum
+        0x75, 0x10, // Report Size (16)
+        0x95, 0x01, // Report Count (1)
+        0x55, 0x0E, // Unit Exponent (-2)
+        0x65, 0x11, // Unit (Centimetre)
+        0x46, width_lo, width_hi, // Physical Maximum
+        0x09, 0x30, // Usage (X)
+        0x81, 0x02, // Input (Data, Variable, Absolute)
+        0x46, height_lo, height_hi, // Physical Maximum
+        0x09, 0x31, // Usage (Y)
+        0x81, 0x02, // Input (Data, Variable, Absolute)
+        0x45, 0x00, // Physical Maximum (0)
+        0x65, 0x00, // Unit (None)
+        0x55, 0x00, // Unit Exponent (0)
+    ]
+}
+
+impl SerializedDescriptor for TouchscreenReport {
+    fn desc() -> &'static [u8] {
+        static DESC: OnceLock<Vec<u8>> = OnceLock::new();
+        DESC.get_or_init(|| {
+            let mut finger = vec![
+                0x09, 0x22, //     Usage (Finger)
+                0xA1, 0x02, //     Collection (Logical)
+                0x09, 0x42, //       Usage (Tip Switch)
+                0x25, 0x01, //       Logical Maximum (1)
+                0x75, 0x01, //       Report Size (1)
+                0x81, 0x02, //       Input (Data, Variable, Absolute)
+                0x75, 0x07, //       Report Size (7)
+                0x81, 0x03, //       Input (Constant)
+                0x09, 0x51, //       Usage (Contact Identifier)
+                0x26, 0xFF, 0x00, // Logical Maximum (255)
+                0x75, 0x08, //       Report Size (8)
+                0x81, 0x02, //       Input (Data, Variable, Absolute)
+            ];
+            finger.extend_from_slice(&digitizer_position());
+            finger.extend_from_slice(&[
+                0x05, 0x0D, //       Usage Page (Digitizer)
+                0xC0,       //     End Collection
+            ]);
+
+            let mut desc = vec![
+                0x05, 0x0D, // Usage Page (Digitizer)
+                0x09, 0x04, // Usage (Touch Screen)
+                0xA1, 0x01, // Collection (Application)
+                0x15, 0x00, //   Logical Minimum (0)
+                0x35, 0x00, //   Physical Minimum (0)
+                0x95, 0x01, //   Report Count (1)
+            ];
+            for _ in 0..TOUCH_MAX_CONTACTS {
+                desc.extend_from_slice(&finger);
+            }
+            // f_hid can't answer GET_REPORT for a feature, so the maximum contact count is sent
+            // with every input report instead.
+            desc.extend_from_slice(&[
+                0x09, 0x54, //   Usage (Contact Count)
+                0x25, 0x7F, //   Logical Maximum (127)
+                0x75, 0x08, //   Report Size (8)
+                0x81, 0x02, //   Input (Data, Variable, Absolute)
+                0x09, 0x55, //   Usage (Contact Count Maximum)
+                0x25, TOUCH_MAX_CONTACTS as u8, // Logical Maximum
+                0x81, 0x03, //   Input (Constant, Variable, Absolute)
+                0xC0,       // End Collection
+            ]);
+            desc
+        })
+    }
+}
+
+impl Serialize for TouchscreenReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Four fields per contact, then the contact count and maximum.
+        let mut s = serializer.serialize_tuple(TOUCH_MAX_CONTACTS * 4 + 2)?;
+        for contact in &self.contacts {
+            s.serialize_element(&(contact.tip as u8))?;
+            s.serialize_element(&contact.id)?;
+            s.serialize_element(&contact.x)?;
+            s.serialize_element(&contact.y)?;
+        }
+        s.serialize_element(&self.count)?;
+        s.serialize_element(&(TOUCH_MAX_CONTACTS as u8))?;
+        s.end()
+    }
+}
+
+impl AsInputReport for TouchscreenReport {}
+
+/// Pen pressure is reported in 0..=PEN_PRESSURE_MAX.
+pub const PEN_PRESSURE_MAX: u16 = 4095;
+
+/// PenReport describes a single-stylus digitizer report and its companion descriptor, used to
+/// drive the host like a graphics tablet.
+#[derive(Debug, Default)]
+pub struct PenReport {
+    pub tip: bool,
+    pub barrel_switch: bool,
+    /// The stylus is held eraser end down, hovering or not.
+    pub invert: bool,
+    /// The eraser end touches the surface, the counterpart of `tip`.
+    pub eraser: bool,
+    pub in_range: bool,
+    pub x: u16,
+    pub y: u16,
+    pub pressure: u16,
+}
+
+impl PenReport {
+    pub const LEN: usize = 7;
+}
+
+impl SerializedDescriptor for PenReport {
+    fn desc() -> &'static [u8] {
+        static DESC: OnceLock<Vec<u8>> = OnceLock::new();
+        DESC.get_or_init(|| {
+            let [pressure_lo, pressure_hi] = PEN_PRESSURE_MAX.to_le_bytes();
+            let mut desc = vec![
+                0x05, 0x0D, // Usage Page (Digitizer)
+                0x09, 0x02, // Usage (Pen)
+                0xA1, 0x01, // Collection (Application)
+                0x09, 0x20, //   Usage (Stylus)
+                0xA1, 0x00, //   Collection (Physical)
+                0x09, 0x42, //     Usage (Tip Switch)
+                0x09, 0x44, //     Usage (Barrel Switch)
+                0x09, 0x3C, //     Usage (Invert)
+                0x09, 0x45, //     Usage (Eraser)
+                0x09, 0x32, //     Usage (In Range)
+                0x15, 0x00, //     Logical Minimum (0)
+                0x25, 0x01, //     Logical Maximum (1)
+                0x35, 0x00, //     Physical Minimum (0)
+                0x75, 0x01, //     Report Size (1)
+                0x95, 0x05, //     Report Count (5)
+                0x81, 0x02, //     Input (Data, Variable, Absolute)
+                0x95, 0x03, //     Report Count (3)
+                0x81, 0x03, //     Input (Constant)
+            ];
+            desc.extend_from_slice(&digitizer_position());
+            desc.extend_from_slice(&[
+                0x05, 0x0D, //     Usage Page (Digitizer)
+                0x09, 0x30, //     Usage (Tip Pressure)
+                0x26, pressure_lo, pressure_hi, // Logical Maximum
+                0x81, 0x02, //     Input (Data, Variable, Absolute)
+                0xC0,       //   End Collection
+                0xC0,       // End Collection
+            ]);
+            desc
+        })
+    }
+}
+
+impl Serialize for PenReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let flags = self.tip as u8
+            | (self.barrel_switch as u8) << 1
+            | (self.invert as u8) << 2
+            | (self.eraser as u8) << 3
+            | (self.in_range as u8) << 4;
+        let mut s = serializer.serialize_tuple(4)?;
+        s.serialize_element(&flags)?;
+        s.serialize_element(&self.x)?;
+        s.serialize_element(&self.y)?;
+        s.serialize_element(&self.pressure)?;
+        s.end()
+    }
+}
+
+impl AsInputReport for PenReport {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize<T: Serialize>(report: &T, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let written = ssmarshal::serialize(&mut buf, report).unwrap();
+        assert_eq!(written, len);
+        buf
+    }
+
+    #[test]
+    fn mouse_report_layout() {
+        let report = MouseReport { buttons: 0b101, x: -1, y: 2, wheel: -3, pan: 4 };
+        assert_eq!(serialize(&report, 5), [0b101, 0xFF, 2, 0xFD, 4]);
+    }
+
+    #[test]
+    fn mouse_feature_report_bits() {
+        assert_eq!(MouseFeatureReport::parse(0), MouseFeatureReport::default());
+        assert_eq!(
+            MouseFeatureReport::parse(0b0001),
+            MouseFeatureReport { wheel_hires: true, pan_hires: false }
+        );
+        assert_eq!(
+            MouseFeatureReport::parse(0b0100),
+            MouseFeatureReport { wheel_hires: false, pan_hires: true }
+        );
+    }
+
+    #[test]
+    fn touchscreen_report_layout() {
+        let mut report = TouchscreenReport::default();
+        report.contacts[1] = TouchContact { tip: true, id: 1, x: 0x1234, y: 0x7FFF };
+        report.count = 1;
+        let buf = serialize(&report, TouchscreenReport::LEN);
+        assert_eq!(buf[..6], [0; 6]);
+        assert_eq!(buf[6..12], [1, 1, 0x34, 0x12, 0xFF, 0x7F]);
+        assert_eq!(buf[TouchscreenReport::LEN - 2..], [1, TOUCH_MAX_CONTACTS as u8]);
+    }
+
+    #[test]
+    fn pen_report_layout() {
+        let report = PenReport { in_range: true, x: 0x0102, y: 0x0304, pressure: 0x0FFF, ..Default::default() };
+        assert_eq!(serialize(&report, PenReport::LEN), [0b10000, 0x02, 0x01, 0x04, 0x03, 0xFF, 0x0F]);
+
+        let buttons = |tip, barrel_switch, invert, eraser| {
+            let report = PenReport { tip, barrel_switch, invert, eraser, ..Default::default() };
+            serialize(&report, PenReport::LEN)[0]
+        };
+        assert_eq!(buttons(true, false, false, false), 0b0001);
+        assert_eq!(buttons(false, true, false, false), 0b0010);
+        assert_eq!(buttons(false, false, true, false), 0b0100);
+        assert_eq!(buttons(false, false, false, true), 0b1000);
+    }
+}
@@ -1,6 +1,3 @@
-use std::fs::File;
-use std::io::Write;
-
 use crate::hid;
 use winit::event::{ElementState, KeyEvent, Modifiers};
 use winit::keyboard::{Key, NamedKey};
@@ -9,12 +6,15 @@ use crate::gadget::{GadgetProcess, IpcCommand};
 pub struct Keyboard {
     report: hid::KeyboardReport,
     pub report_buf: Vec<u8>,
+    // Modifier bits held on behalf of touch gestures, e.g. Ctrl while pinch zooming.
+    held_modifiers: u8,
 }
 
 impl Keyboard {
     pub fn new() -> Self {
         Self {
             report_buf: vec![0; 8],
+            held_modifiers: 0,
             report: hid::KeyboardReport {
                 modifier: 0,
                 reserved: 0,
@@ -56,6 +56,27 @@ impl Keyboard {
     }
 
     pub fn handle_modifiers(&mut self, _mods_event: Modifiers) {}
+
+    /// Presses the given modifier bits until they're released with [`Keyboard::release_modifiers`].
+    pub fn hold_modifiers(&mut self, modifiers: u8, gadget: &GadgetProcess) {
+        self.held_modifiers |= modifiers;
+        self.send_modifiers(gadget);
+    }
+
+    pub fn release_modifiers(&mut self, modifiers: u8, gadget: &GadgetProcess) {
+        self.held_modifiers &= !modifiers;
+        self.send_modifiers(gadget);
+    }
+
+    fn send_modifiers(&mut self, gadget: &GadgetProcess) {
+        if self.report.modifier == self.held_modifiers {
+            return;
+        }
+        self.report.modifier = self.held_modifiers;
+        ssmarshal::serialize(self.report_buf.as_mut_slice(), &self.report)
+            .expect("report serialization");
+        gadget.send(IpcCommand::KeyboardReport(self.report_buf.clone())).unwrap();
+    }
 }
 
 fn keyboard_usage(key_event: KeyEvent) -> Option<u8> {
@@ -72,7 +93,7 @@ fn keyboard_usage(key_event: KeyEvent) -> Option<u8> {
             .to_lowercase()
             .chars()
             .next()
-            .map(|char| match char {
+            .and_then(|char| match char {
                 char @ 'a'..='z' => Some(4 + (char as u32 - ('a' as u32)) as u8),
                 char @ '1'..='9' => Some(0x1E + (char as u32 - ('1' as u32)) as u8),
                 '!' => Some(0x1E),
@@ -91,8 +112,7 @@ fn keyboard_usage(key_event: KeyEvent) -> Option<u8> {
                 ']' | '}' => Some(0x30),
                 '\\' | '|' => Some(0x31),
                 _ => None,
-            })
-            .flatten(),
+            }),
         Key::Named(named) => match named {
             NamedKey::Enter => Some(0x28),
             NamedKey::Escape => Some(0x29),
@@ -1,21 +1,34 @@
 #![deny(clippy::all)]
 #![forbid(unsafe_code)]
 
+mod backlight;
 mod display;
+mod edid;
+mod formats;
 mod gadget;
+mod gud;
 mod hid;
 mod keyboard;
+mod modes;
 mod mouse;
+mod orientation;
+mod pen;
+mod renderer;
+mod touchscreen;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use anyhow::Context;
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
+use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
-use winit::event::{Event, StartCause, WindowEvent};
+use winit::event::{DeviceEvent, ElementState, Event, StartCause, TouchPhase, WindowEvent};
+use winit::keyboard::{Key, ModifiersState};
 use winit::event_loop::{ControlFlow, EventLoopBuilder};
 use winit::window::WindowBuilder;
 use crate::gadget::{GadgetEvent, IpcCommand};
@@ -25,6 +38,147 @@ use crate::gadget::{GadgetEvent, IpcCommand};
 struct Args {
     #[arg(short, long)]
     gadget: Option<String>,
+
+    /// How the window is split between the mirrored display and touch controls. The trackpad
+    /// layout requires --touch-mode mouse.
+    #[arg(long, value_enum, default_value_t)]
+    layout: display::Layout,
+
+    /// Turns the mirrored display clockwise, in degrees. Cycle at runtime with Ctrl+Alt+R, e.g.
+    /// to follow the device when the compositor's orientation is locked.
+    #[arg(long, value_enum, default_value_t)]
+    rotation: display::Rotation,
+
+    /// Turn the mirrored display with the device, read from its accelerometer, for when the
+    /// compositor's orientation is locked. Starts from --rotation.
+    #[arg(long)]
+    follow_orientation: bool,
+
+    /// How the mirrored display is scaled into the window. Cycle at runtime with Ctrl+Alt+S. When
+    /// it overflows the window, drag with three fingers to pan around it.
+    #[arg(long, value_enum, default_value_t)]
+    scale_mode: display::ScaleMode,
+
+    /// Pinch with three fingers to zoom into the mirrored display locally, without the host
+    /// knowing, and drag them to pan. Pinching all the way out or Ctrl+Alt+0 resets the view. Only
+    /// applies to the direct layout.
+    #[arg(long)]
+    local_zoom: bool,
+
+    /// Where sysfs is mounted, for finding the panel backlight the host's brightness setting
+    /// controls. Handed on to the gadget process.
+    #[arg(long, default_value = "/sys")]
+    sysfs_root: PathBuf,
+
+    /// Number of click buttons along the bottom of the virtual trackpad.
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(0..=3))]
+    trackpad_buttons: u8,
+
+    /// Start the mouse jiggler once the gadget is bound, keeping the host from locking or
+    /// sleeping. Toggle at runtime with Ctrl+Alt+J.
+    #[arg(long)]
+    jiggle: bool,
+
+    /// Seconds between mouse jiggles.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    jiggle_interval: u64,
+
+    /// How touches on the window are forwarded to the host.
+    #[arg(long, value_enum, default_value_t)]
+    touch_mode: TouchMode,
+
+    /// Two-finger scrolling moves the content in the same direction as the fingers.
+    #[arg(long)]
+    natural_scroll: bool,
+
+    /// Multiplier applied to two-finger scroll distance.
+    #[arg(long, default_value_t = 1.0)]
+    scroll_speed: f64,
+
+    /// Acceleration applied to single-finger touch motion.
+    #[arg(long, value_enum, default_value_t)]
+    accel_profile: mouse::AccelProfile,
+
+    /// Multiplier applied to touch motion on top of the acceleration profile.
+    #[arg(long, default_value_t = 1.0)]
+    sensitivity: f64,
+
+    /// What a two-finger pinch is sent to the host as.
+    #[arg(long, value_enum, default_value_t)]
+    pinch_zoom: mouse::PinchZoom,
+
+    /// How single-finger touches position the host pointer.
+    #[arg(long, value_enum, default_value_t)]
+    pointing: mouse::Pointing,
+
+    /// Host mouse counts per framebuffer pixel (x,y) for homing. Run the calibration wizard with
+    /// Ctrl+Alt+C to measure it.
+    #[arg(long, value_delimiter = ',', num_args = 2, default_values_t = [1.0, 1.0])]
+    homing_scale: Vec<f64>,
+
+    /// Draw a marker where the host pointer is predicted to be, ahead of the next frame. A
+    /// three-finger long-press homes the pointer to resynchronise it.
+    #[arg(long)]
+    cursor_overlay: bool,
+
+    /// Show a magnified loupe under a single finger and only tap the host once it's lifted.
+    /// Requires --touch-mode digitizer.
+    #[arg(long)]
+    loupe: bool,
+
+    /// Which mouse buttons touch gestures press. The options below rebind single gestures on top
+    /// of it.
+    #[arg(long, value_enum, default_value_t)]
+    bindings: mouse::BindingProfile,
+
+    /// Mouse button pressed by a one-finger tap.
+    #[arg(long, value_enum)]
+    tap: Option<mouse::TouchButton>,
+
+    /// Mouse button pressed by a two-finger tap.
+    #[arg(long, value_enum)]
+    two_finger_tap: Option<mouse::TouchButton>,
+
+    /// Mouse button pressed by a three-finger tap.
+    #[arg(long, value_enum)]
+    three_finger_tap: Option<mouse::TouchButton>,
+
+    /// Mouse button pressed by a four-finger tap.
+    #[arg(long, value_enum)]
+    four_finger_tap: Option<mouse::TouchButton>,
+
+    /// Mouse button pressed by holding a finger still. Moving afterwards drags with it held.
+    #[arg(long, value_enum)]
+    long_press: Option<mouse::TouchButton>,
+
+    /// Mouse button pressed by holding two fingers still.
+    #[arg(long, value_enum)]
+    two_finger_long_press: Option<mouse::TouchButton>,
+}
+
+impl Args {
+    /// The binding profile, with any gestures bound individually replaced.
+    fn touch_bindings(&self) -> mouse::TouchBindings {
+        let mut bindings = self.bindings.bindings();
+        let taps = [self.tap, self.two_finger_tap, self.three_finger_tap, self.four_finger_tap];
+        for (binding, tap) in bindings.taps.iter_mut().zip(taps) {
+            *binding = tap.unwrap_or(*binding);
+        }
+        bindings.long_press = self.long_press.unwrap_or(bindings.long_press);
+        bindings.two_finger_long_press = self.two_finger_long_press.unwrap_or(bindings.two_finger_long_press);
+        bindings
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+enum TouchMode {
+    /// Touches drive a relative mouse, two fingers scroll.
+    #[default]
+    Mouse,
+    /// Every touch point is forwarded to a multi-touch digitizer, gestures are left to the host.
+    Digitizer,
+    /// Touches are forwarded as a pressure-sensitive stylus.
+    Pen,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -34,21 +188,39 @@ fn main() -> anyhow::Result<()> {
         .init();
 
     let args = Args::parse();
+    if args.loupe && args.touch_mode != TouchMode::Digitizer {
+        // The other touch modes don't hold touches back, so there'd be no time to aim.
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--loupe requires --touch-mode digitizer")
+            .exit();
+    }
+    if args.layout == display::Layout::Trackpad && args.touch_mode != TouchMode::Mouse {
+        // The digitizer and pen place touches on the host screen, which the trackpad can't.
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--layout trackpad requires --touch-mode mouse")
+            .exit();
+    }
 
     if let Some(path) = args.gadget {
-        gadget::run(path)
+        gadget::run(path, &args.sysfs_root)
     } else {
-        run()
+        run(args)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AppEvent {
     DisplayFrameArrived,
+    /// The host switched to a different display mode.
+    DisplayModeChanged,
+    /// The host set the backlight brightness, as a percentage.
+    BacklightSet(u8),
+    /// The device was turned, and the mirrored display with it.
+    OrientationChanged(display::Rotation),
     Gadget(GadgetEvent),
 }
 
-fn run() -> anyhow::Result<()> {
+fn run(args: Args) -> anyhow::Result<()> {
     let event_loop = EventLoopBuilder::<AppEvent>::with_user_event()
         .build()
         .unwrap();
@@ -62,22 +234,56 @@ fn run() -> anyhow::Result<()> {
     };
     event_loop.set_control_flow(ControlFlow::Wait);
 
-    let gadget = gadget::spawn(event_loop.create_proxy()).context("failed to spawn gadget")?;
+    let gadget = gadget::spawn(event_loop.create_proxy(), &args.sysfs_root).context("failed to spawn gadget")?;
 
-    let mut display = display::Display::new(event_loop.create_proxy(), &window);
+    let mut display = display::Display::new(
+        event_loop.create_proxy(),
+        &window,
+        args.layout,
+        args.trackpad_buttons as usize,
+        args.rotation,
+        args.scale_mode,
+        args.local_zoom,
+    );
+    if args.follow_orientation {
+        orientation::watch(event_loop.create_proxy(), args.rotation).context("failed to follow device orientation")?;
+    }
     let mut kb = keyboard::Keyboard::new();
-    let mut mouse = mouse::Mouse::new();
+    let mut touchscreen = touchscreen::Touchscreen::new(args.loupe);
+    let mut pen = pen::Pen::new();
+    let mut modifiers = ModifiersState::empty();
+    let mut jiggling = false;
+    // The virtual monitor is plugged in while the window is up and visible. Ctrl+Alt+U unplugs it
+    // regardless.
+    let mut unplugged = false;
+    let mut occluded = false;
+    let jiggle_interval = Duration::from_secs(args.jiggle_interval);
+    let mut mouse = mouse::Mouse::new(mouse::MouseConfig {
+        natural_scroll: args.natural_scroll,
+        scroll_speed: args.scroll_speed,
+        accel_profile: args.accel_profile,
+        sensitivity: args.sensitivity,
+        pinch_zoom: args.pinch_zoom,
+        bindings: args.touch_bindings(),
+        pointing: args.pointing,
+        homing_scale: (args.homing_scale[0], args.homing_scale[1]),
+    }, display.framebuffer_size());
+    if args.cursor_overlay {
+        display.set_cursor_overlay(Some(mouse.estimated_cursor()));
+    }
 
     event_loop
         .run(move |event, elwt| {
             match event {
                 Event::NewEvents(StartCause::Init) => {
+                    // The window is up, plug the virtual monitor in.
+                    display.set_connected(true);
                     window.request_redraw();
                 }
                 Event::UserEvent(app_event) => match app_event {
                     AppEvent::Gadget(gadget_event) => match gadget_event {
                         GadgetEvent::Registered(path) => {
-                            println!("gadget registered at {} yey", path);
+                            info!("gadget registered, GUD function at {}", path);
                             display.setup(path).unwrap();
                             gadget.send(IpcCommand::Bind).unwrap();
                         }
@@ -86,39 +292,204 @@ fn run() -> anyhow::Result<()> {
                             panic!("gadget process died onoes");
                         }
                         GadgetEvent::Bound => {
-                            println!("gadget bound");
+                            info!("gadget bound");
+                            if args.jiggle {
+                                jiggling = true;
+                                gadget.send(IpcCommand::SetJiggler(Some(jiggle_interval))).unwrap();
+                            }
+                        }
+                        GadgetEvent::JigglerStopped => {
+                            jiggling = false;
+                        }
+                        GadgetEvent::MouseFeatures(features) => {
+                            mouse.set_features(features);
+                        }
+                        GadgetEvent::Backlight(percent) => {
+                            display.set_backlight(percent);
                         }
                     }
                     AppEvent::DisplayFrameArrived => {
                         window.request_redraw()
                     },
+                    AppEvent::DisplayModeChanged => {
+                        mouse.set_bounds(display.framebuffer_size());
+                        if args.cursor_overlay {
+                            display.set_cursor_overlay(Some(mouse.estimated_cursor()));
+                        }
+                        window.request_redraw()
+                    },
+                    AppEvent::BacklightSet(percent) => {
+                        gadget.send(IpcCommand::SetBacklight(percent)).unwrap();
+                    },
+                    AppEvent::OrientationChanged(rotation) => {
+                        display.set_rotation(rotation, window.scale_factor());
+                        window.request_redraw()
+                    },
                 },
                 Event::WindowEvent {
                     event: window_event,
                     ..
                 } => match window_event {
                     WindowEvent::CloseRequested => {
+                        // Unplug first so the host moves its windows off the monitor.
+                        display.set_connected(false);
                         elwt.exit();
                     }
+                    WindowEvent::Occluded(hidden) => {
+                        occluded = hidden;
+                        display.set_connected(!occluded && !unplugged);
+                    }
                     WindowEvent::RedrawRequested => {
                         display.render();
                     }
                     WindowEvent::Resized(size) => {
-                        display.resize(size.width, size.height);
+                        display.resize(size, window.scale_factor());
+                        mouse.set_bounds(display.framebuffer_size());
                     }
                     WindowEvent::ModifiersChanged(mods_event) => {
+                        modifiers = mods_event.state();
+                        // Ctrl+Alt releases a captured pointer, much like VM consoles do.
+                        if modifiers.control_key() && modifiers.alt_key() {
+                            mouse.set_captured(&window, false);
+                        }
                         kb.handle_modifiers(mods_event);
                     }
                     WindowEvent::KeyboardInput {
                         event: key_event, ..
                     } => {
-                        kb.handle_key(key_event, &gadget);
+                        let shortcut = match &key_event.logical_key {
+                            Key::Character(c) if modifiers.control_key() && modifiers.alt_key() => {
+                                Some(c.to_lowercase())
+                            }
+                            _ => None,
+                        };
+                        let pressed = key_event.state == ElementState::Pressed && !key_event.repeat;
+                        match shortcut.as_deref() {
+                            Some("j") => {
+                                if pressed {
+                                    jiggling = !jiggling;
+                                    let interval = jiggling.then_some(jiggle_interval);
+                                    gadget.send(IpcCommand::SetJiggler(interval)).unwrap();
+                                }
+                            }
+                            Some("c") => {
+                                if pressed {
+                                    mouse.start_calibration(&gadget);
+                                    display.set_calibration_target(mouse.calibration_target());
+                                    window.request_redraw();
+                                }
+                            }
+                            Some("s") => {
+                                if pressed {
+                                    display.set_scale_mode(display.scale_mode().next());
+                                    window.request_redraw();
+                                }
+                            }
+                            Some("u") => {
+                                if pressed {
+                                    unplugged = !unplugged;
+                                    display.set_connected(!occluded && !unplugged);
+                                }
+                            }
+                            Some("0") => {
+                                if pressed {
+                                    display.reset_view();
+                                    window.request_redraw();
+                                }
+                            }
+                            Some("r") => {
+                                if pressed {
+                                    display.set_rotation(display.rotation().next(), window.scale_factor());
+                                    window.request_redraw();
+                                }
+                            }
+                            _ => kb.handle_key(key_event, &gadget),
+                        }
                     }
                     WindowEvent::Touch(touch) => {
-                        mouse.handle_touch(touch, &gadget);
+                        let touches = match display.handle_pan(touch) {
+                            display::Pan::Pass => vec![touch],
+                            display::Pan::Consumed => {
+                                window.request_redraw();
+                                vec![]
+                            }
+                            display::Pan::Started(cancelled) => cancelled,
+                        };
+                        for mut touch in touches {
+                            // Gestures and pointing work in host framebuffer pixels rather than window
+                            // pixels. Touches starting in the letterbox bars are ignored, those that
+                            // wander into them are clamped to the edge.
+                            touch.location = match display.window_to_buffer(touch.location) {
+                                Ok(pos) => pos,
+                                Err(_) if touch.phase == TouchPhase::Started => continue,
+                                Err(pos) => pos,
+                            };
+                            match args.touch_mode {
+                                TouchMode::Mouse => {
+                                    let region = display.region_at(touch.location);
+                                    let calibrating = mouse.calibration_target().is_some();
+                                    // Above the virtual trackpad the mirrored display is only there
+                                    // to be looked at, other than for the calibration tap.
+                                    if args.layout == display::Layout::Trackpad
+                                        && region == display::TouchRegion::Display
+                                        && touch.phase == TouchPhase::Started
+                                        && !calibrating
+                                    {
+                                        continue;
+                                    }
+                                    if args.pointing == mouse::Pointing::Homing || calibrating {
+                                        // The pointer is placed where the touch points on the host
+                                        // framebuffer, even from the virtual trackpad.
+                                        touch.location = display.absolute_position(touch.location);
+                                    }
+                                    mouse.handle_touch(touch, region, &mut kb, &gadget);
+                                    if args.cursor_overlay {
+                                        display.set_cursor_overlay(Some(mouse.estimated_cursor()));
+                                    }
+                                    if calibrating {
+                                        display.set_calibration_target(mouse.calibration_target());
+                                    }
+                                    window.request_redraw();
+                                }
+                                TouchMode::Digitizer => {
+                                    touchscreen.handle_touch(touch, display.framebuffer_size(), &gadget);
+                                    if args.loupe {
+                                        display.set_loupe(touchscreen.loupe_position());
+                                        window.request_redraw();
+                                    }
+                                }
+                                TouchMode::Pen => pen.handle_touch(touch, display.framebuffer_size(), &gadget),
+                            }
+                        }
+                    }
+                    WindowEvent::Focused(false) => {
+                        mouse.set_captured(&window, false);
+                    }
+                    WindowEvent::MouseInput { state, button, .. } => {
+                        if !mouse.is_captured() {
+                            // The first click only grabs the pointer, it isn't forwarded.
+                            if state == ElementState::Pressed {
+                                mouse.set_captured(&window, true);
+                            }
+                        } else {
+                            mouse.handle_button(button, state, &gadget);
+                        }
+                    }
+                    WindowEvent::MouseWheel { delta, .. } if mouse.is_captured() => {
+                        mouse.handle_wheel(delta, &gadget);
                     }
                     _ => {}
                 },
+                Event::DeviceEvent {
+                    event: DeviceEvent::MouseMotion { delta },
+                    ..
+                } if mouse.is_captured() => {
+                    mouse.handle_motion(delta, &gadget);
+                    if args.cursor_overlay {
+                        display.set_cursor_overlay(Some(mouse.estimated_cursor()));
+                        window.request_redraw();
+                    }
+                }
                 _ => {}
             }
         })
@@ -132,3 +503,42 @@ pub fn wait_for_path<T: AsRef<Path>>(path: T) {
         std::thread::sleep(std::time::Duration::from_millis(100));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sysfs_root_defaults_to_sys() {
+        let args = Args::try_parse_from(["usb-kvm"]).unwrap();
+        assert_eq!(args.sysfs_root, Path::new("/sys"));
+    }
+
+    #[test]
+    fn gadget_process_gets_the_sysfs_root() {
+        let root = backlight::fake_sysfs("process_args", Some((51, 255)));
+        let process_args = gadget::process_args("channel".into(), &root);
+        let args = Args::try_parse_from(std::iter::once("usb-kvm".into()).chain(process_args)).unwrap();
+        assert_eq!(args.gadget.as_deref(), Some("channel"));
+
+        let backlight = gadget::find_backlight(&args.sysfs_root).expect("backlight under the sysfs root");
+        assert_eq!(backlight.lock().unwrap().percent(), 20);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn gestures_rebind_the_profile() {
+        use mouse::TouchButton::*;
+        let args = Args::try_parse_from(["usb-kvm"]).unwrap();
+        assert_eq!(args.touch_bindings(), mouse::BindingProfile::Desktop.bindings());
+        assert_eq!(args.touch_bindings().two_finger_long_press, Forward);
+
+        let args = Args::try_parse_from([
+            "usb-kvm", "--bindings", "touch", "--three-finger-tap", "none", "--long-press", "left",
+        ]).unwrap();
+        let bindings = args.touch_bindings();
+        assert_eq!(bindings.taps, [Left, Back, None, Middle]);
+        assert_eq!(bindings.long_press, Left);
+        assert_eq!(bindings.two_finger_long_press, Middle);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::gud::{
+    DisplayMode, GUD_DISPLAY_MODE_FLAG_NVSYNC, GUD_DISPLAY_MODE_FLAG_PHSYNC, GUD_DISPLAY_MODE_FLAG_PREFERRED,
+};
+
+const REFRESH_RATE: u32 = 60;
+
+// CVT reduced blanking (v1) constants. Horizontal blanking is fixed, vertical blanking is however
+// many lines it takes to cover the minimum blanking time.
+const RB_H_BLANK: u32 = 160;
+const RB_H_FRONT_PORCH: u32 = 48;
+const RB_H_SYNC: u32 = 32;
+const RB_MIN_V_BLANK_US: f64 = 460.0;
+const RB_V_FRONT_PORCH: u32 = 3;
+const RB_MIN_V_BACK_PORCH: u32 = 6;
+/// The pixel clock is rounded down to a multiple of this, in kHz.
+const RB_CLOCK_STEP: u32 = 250;
+
+/// Standard modes advertised alongside the native one, landscape and portrait.
+const STANDARD_MODES: &[(u32, u32)] = &[
+    (1920, 1080),
+    (1280, 720),
+    (1024, 768),
+    (1080, 1920),
+    (720, 1280),
+    (768, 1024),
+];
+
+/// Generates CVT reduced blanking timings for a mode at 60Hz.
+pub fn cvt_rb(width: u32, height: u32, preferred: bool) -> DisplayMode {
+    let htotal = width + RB_H_BLANK;
+    let hsync_start = width + RB_H_FRONT_PORCH;
+    let hsync_end = hsync_start + RB_H_SYNC;
+
+    let vsync = vsync_width(width, height);
+    let h_period_us = (1_000_000.0 / REFRESH_RATE as f64 - RB_MIN_V_BLANK_US) / height as f64;
+    let v_blank = ((RB_MIN_V_BLANK_US / h_period_us) as u32 + 1)
+        .max(RB_V_FRONT_PORCH + vsync + RB_MIN_V_BACK_PORCH);
+    let vtotal = height + v_blank;
+    let vsync_start = height + RB_V_FRONT_PORCH;
+    let vsync_end = vsync_start + vsync;
+
+    let clock = (REFRESH_RATE as u64 * htotal as u64 * vtotal as u64 / 1000) as u32 / RB_CLOCK_STEP * RB_CLOCK_STEP;
+
+    let mut flags = GUD_DISPLAY_MODE_FLAG_PHSYNC | GUD_DISPLAY_MODE_FLAG_NVSYNC;
+    if preferred {
+        flags |= GUD_DISPLAY_MODE_FLAG_PREFERRED;
+    }
+
+    DisplayMode {
+        clock,
+        hdisplay: width as u16,
+        hsync_start: hsync_start as u16,
+        hsync_end: hsync_end as u16,
+        htotal: htotal as u16,
+        vdisplay: height as u16,
+        vsync_start: vsync_start as u16,
+        vsync_end: vsync_end as u16,
+        vtotal: vtotal as u16,
+        flags,
+    }
+}
+
+/// CVT encodes the aspect ratio in the vsync width so that sinks can tell modes apart.
+fn vsync_width(width: u32, height: u32) -> u32 {
+    if width * 3 == height * 4 {
+        4
+    } else if width * 9 == height * 16 {
+        5
+    } else if width * 10 == height * 16 {
+        6
+    } else if width * 4 == height * 5 || width * 9 == height * 15 {
+        7
+    } else {
+        10
+    }
+}
+
+/// The modes offered to the host: the native one matching the window first and marked preferred,
+/// followed by whichever standard modes fit within `max`.
+pub fn display_modes(native: (u32, u32), max: u32) -> Vec<DisplayMode> {
+    let mut modes = vec![cvt_rb(native.0, native.1, true)];
+    for &(width, height) in STANDARD_MODES {
+        if (width, height) != native && width <= max && height <= max {
+            modes.push(cvt_rb(width, height, false));
+        }
+    }
+    modes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Timings from the VESA DMT list, which uses CVT-RB for these modes.
+    #[test]
+    fn cvt_rb_matches_vesa() {
+        let mode = cvt_rb(1920, 1080, false);
+        assert_eq!((mode.hsync_start, mode.hsync_end, mode.htotal), (1968, 2000, 2080));
+        assert_eq!((mode.vsync_start, mode.vsync_end, mode.vtotal), (1083, 1088, 1111));
+        assert_eq!(mode.clock, 138_500);
+
+        let mode = cvt_rb(1024, 768, false);
+        assert_eq!((mode.htotal, mode.vsync_end, mode.vtotal), (1184, 775, 790));
+        assert_eq!(mode.clock, 56_000);
+    }
+
+    #[test]
+    fn cvt_rb_flags() {
+        let flags = GUD_DISPLAY_MODE_FLAG_PHSYNC | GUD_DISPLAY_MODE_FLAG_NVSYNC;
+        assert_eq!(cvt_rb(1280, 720, false).flags, flags);
+        assert_eq!(cvt_rb(1280, 720, true).flags, flags | GUD_DISPLAY_MODE_FLAG_PREFERRED);
+    }
+
+    #[test]
+    fn native_mode_comes_first_and_is_not_repeated() {
+        let modes = display_modes((1280, 720), 1280);
+        assert_eq!((modes[0].hdisplay, modes[0].vdisplay), (1280, 720));
+        assert!(modes[0].flags & GUD_DISPLAY_MODE_FLAG_PREFERRED != 0);
+        let sizes: Vec<_> = modes[1..].iter().map(|m| (m.hdisplay, m.vdisplay)).collect();
+        assert_eq!(sizes, [(1024, 768), (720, 1280), (768, 1024)]);
+    }
+}
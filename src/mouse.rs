@@ -1,18 +1,270 @@
 use crate::hid;
-use std::fs::File;
-use std::io::Write;
-use winit::dpi::PhysicalPosition;
-use winit::event::{Touch, TouchPhase};
-use crate::gadget::GadgetProcess;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use clap::ValueEnum;
+use tracing::{info, warn};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, Touch, TouchPhase};
+use winit::window::{CursorGrabMode, Window};
+use crate::gadget::{GadgetProcess, IpcCommand};
+use crate::display::TouchRegion;
+use crate::keyboard::Keyboard;
+
+/// How many pixels of two-finger travel make up a single wheel/pan notch at a scroll speed of 1.0.
+const SCROLL_STEP: f64 = 20.0;
+
+/// Touches lifted within this long without moving are taps.
+const TAP_TIMEOUT: Duration = Duration::from_millis(200);
+/// Touches held still for at least this long are long-presses.
+const LONG_PRESS_TIMEOUT: Duration = Duration::from_millis(500);
+/// How far (in pixels) fingers may wander during a tap or long-press.
+const TAP_SLOP: f64 = 10.0;
+
+/// How far (in pixels) the distance between two fingers must change before it's treated as a pinch.
+const PINCH_THRESHOLD: f64 = 40.0;
+/// How far (in pixels) two fingers must move together before it's treated as a scroll.
+const TWO_FINGER_SCROLL_THRESHOLD: f64 = 15.0;
+/// How many wheel notches it takes to pinch the fingers twice as far apart.
+const PINCH_NOTCHES_PER_DOUBLING: f64 = 4.0;
+
+/// Reports of maximum negative motion sent to slam the host pointer into the top-left corner.
+/// Enough to cross a 4K screen even without any host-side acceleration.
+const HOMING_SLAM_REPORTS: usize = 40;
+/// Largest delta per report when moving the pointer to a calibrated position. Kept small so that
+/// host pointer acceleration (if any) doesn't kick in and throw off the calibration.
+const HOMING_STEP: f64 = 8.0;
+/// How far (in mouse counts) the calibration wizard moves the pointer away from the corner.
+const CALIBRATION_COUNTS: f64 = 400.0;
+
+/// Finger speed (in pixels per millisecond) below which the adaptive profile doesn't accelerate.
+const ADAPTIVE_THRESHOLD: f64 = 0.4;
+/// How quickly the adaptive profile ramps up once past the threshold.
+const ADAPTIVE_INCLINE: f64 = 1.1;
+/// Upper bound of the adaptive acceleration factor.
+const ADAPTIVE_MAX: f64 = 3.5;
+/// Gain per pixel-per-millisecond of finger speed for the linear profile.
+const LINEAR_GAIN: f64 = 0.8;
+
+/// Pointer acceleration applied to single-finger touch motion before it's sent to the host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum AccelProfile {
+    /// Finger travel maps to host motion by a constant factor.
+    Flat,
+    /// Motion is scaled proportionally to finger speed.
+    Linear,
+    /// Slow movement stays precise, faster movement ramps up to a capped factor, similar to
+    /// libinput's adaptive profile.
+    #[default]
+    Adaptive,
+}
+
+impl AccelProfile {
+    /// Returns the factor to scale a delta by, given the finger speed in pixels per millisecond.
+    fn factor(self, speed: f64) -> f64 {
+        match self {
+            AccelProfile::Flat => 1.0,
+            AccelProfile::Linear => 1.0 + speed * LINEAR_GAIN,
+            AccelProfile::Adaptive if speed < ADAPTIVE_THRESHOLD => 1.0,
+            AccelProfile::Adaptive => {
+                (1.0 + (speed - ADAPTIVE_THRESHOLD) * ADAPTIVE_INCLINE).min(ADAPTIVE_MAX)
+            }
+        }
+    }
+}
+
+/// What a two-finger pinch is sent to the host as. Spreading the fingers scrolls the wheel up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum PinchZoom {
+    /// Ctrl+wheel, which zooms in most browsers and document viewers.
+    #[default]
+    Ctrl,
+    Shift,
+    Alt,
+    Meta,
+    /// The wheel alone.
+    Wheel,
+    /// Pinching is ignored, two fingers only scroll.
+    Off,
+}
+
+impl PinchZoom {
+    /// The keyboard modifier bits held for the duration of the pinch.
+    fn modifiers(self) -> u8 {
+        match self {
+            PinchZoom::Ctrl => 0x01,
+            PinchZoom::Shift => 0x02,
+            PinchZoom::Alt => 0x04,
+            PinchZoom::Meta => 0x08,
+            PinchZoom::Wheel | PinchZoom::Off => 0,
+        }
+    }
+}
+
+/// How single-finger touches position the host pointer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Pointing {
+    /// Finger movement moves the pointer, like a trackpad.
+    #[default]
+    Relative,
+    /// Touching a point moves the pointer there, by slamming it into the top-left corner and then
+    /// moving a calibrated distance. For hosts that only accept a relative mouse, like firmware
+    /// setup screens and bootloaders. With the virtual trackpad, the pad stands for the whole
+    /// host screen.
+    Homing,
+}
+
+/// A mouse button that a touch gesture can be bound to.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TouchButton {
+    Left,
+    Right,
+    Middle,
+    /// Button 4
+    Back,
+    /// Button 5
+    Forward,
+    /// The gesture does nothing.
+    None,
+}
+
+impl TouchButton {
+    /// The bit in [`hid::MouseReport::buttons`] for this button.
+    fn bit(self) -> Option<u8> {
+        match self {
+            TouchButton::Left => Some(0),
+            TouchButton::Right => Some(1),
+            TouchButton::Middle => Some(2),
+            TouchButton::Back => Some(3),
+            TouchButton::Forward => Some(4),
+            TouchButton::None => None,
+        }
+    }
+}
+
+/// Which mouse buttons taps and long-presses press on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TouchBindings {
+    /// Indexed by the number of fingers in the tap, minus one.
+    pub taps: [TouchButton; 4],
+    /// A single finger held still. Moving after the long-press drags with the button held.
+    pub long_press: TouchButton,
+    /// Two fingers held still, then lifted.
+    pub two_finger_long_press: TouchButton,
+}
+
+/// Sets of touch bindings to start from, before any gesture is bound individually.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum BindingProfile {
+    /// Taps click with one to four fingers: left, right, middle and back. A long-press drags with
+    /// the left button and a two-finger long-press goes forward.
+    #[default]
+    Desktop,
+    /// A long-press opens the context menu like on phones and tablets. Two- and three-finger taps
+    /// go back and forward, four fingers and a two-finger long-press middle click.
+    Touch,
+}
+
+impl BindingProfile {
+    pub fn bindings(self) -> TouchBindings {
+        use TouchButton::*;
+        match self {
+            BindingProfile::Desktop => TouchBindings {
+                taps: [Left, Right, Middle, Back],
+                long_press: Left,
+                two_finger_long_press: Forward,
+            },
+            BindingProfile::Touch => TouchBindings {
+                taps: [Left, Back, Forward, Middle],
+                long_press: Right,
+                two_finger_long_press: Middle,
+            },
+        }
+    }
+}
+
+/// Tracks a touch interaction from the first finger down until the last one is lifted, to
+/// recognise taps and long-presses.
+struct TouchSession {
+    started: Instant,
+    max_fingers: usize,
+    travel: f64,
+    held: Option<u8>,
+}
+
+/// Two-finger touches are either a scroll or a pinch, decided once the fingers have moved far
+/// enough to tell and then kept until they're lifted.
+#[derive(Debug, PartialEq)]
+enum TwoFingerGesture {
+    Undecided { distance: f64, midpoint: (f64, f64) },
+    Scroll,
+    Pinch { distance: f64 },
+}
+
+impl TwoFingerGesture {
+    /// Tells a pinch from a scroll by how far the fingers' distance and midpoint have moved since
+    /// they landed, or `None` while they haven't moved far enough to tell.
+    fn decide(
+        (start_distance, start_midpoint): (f64, (f64, f64)),
+        (distance, midpoint): (f64, (f64, f64)),
+        pinch_allowed: bool,
+    ) -> Option<Self> {
+        if pinch_allowed && (distance - start_distance).abs() > PINCH_THRESHOLD {
+            Some(TwoFingerGesture::Pinch { distance })
+        } else if (midpoint.0 - start_midpoint.0).hypot(midpoint.1 - start_midpoint.1) > TWO_FINGER_SCROLL_THRESHOLD {
+            Some(TwoFingerGesture::Scroll)
+        } else {
+            None
+        }
+    }
+}
+
+/// The wheel notches, possibly fractional, that pinching from one finger distance to another is
+/// worth. Spreading the fingers is positive.
+fn pinch_notches(last_distance: f64, distance: f64) -> f64 {
+    (distance / last_distance.max(1.0)).log2() * PINCH_NOTCHES_PER_DOUBLING
+}
+
+pub struct MouseConfig {
+    pub natural_scroll: bool,
+    pub scroll_speed: f64,
+    pub accel_profile: AccelProfile,
+    pub sensitivity: f64,
+    pub pinch_zoom: PinchZoom,
+    pub bindings: TouchBindings,
+    pub pointing: Pointing,
+    /// Host mouse counts per framebuffer pixel along each axis, used by [`Pointing::Homing`] and
+    /// to estimate where the host pointer is.
+    pub homing_scale: (f64, f64),
+}
 
 pub struct Mouse {
     report: hid::MouseReport,
     pub report_buf: Vec<u8>,
-    active_touch: Option<(u64, PhysicalPosition<f64>)>,
+    touches: HashMap<u64, PhysicalPosition<f64>>,
+    // Touches held down on the virtual trackpad's click buttons, and the button bit they press.
+    button_touches: HashMap<u64, u8>,
+    // Fractional wheel/pan units that haven't been sent to the host yet.
+    scroll_remainder: (f64, f64),
+    // Fractional motion that hasn't been sent to the host yet.
+    motion_remainder: (f64, f64),
+    last_motion: Option<Instant>,
+    gesture: Option<TwoFingerGesture>,
+    session: Option<TouchSession>,
+    // The calibration wizard is waiting for the user to tap where the host pointer ended up.
+    calibrating: bool,
+    // Where the host pointer should be in framebuffer pixels, going by the reports sent since it
+    // was last homed.
+    estimated_cursor: (f64, f64),
+    bounds: PhysicalSize<u32>,
+    config: MouseConfig,
+    features: hid::MouseFeatureReport,
+    captured: bool,
 }
 
 impl Mouse {
-    pub fn new() -> Self {
+    /// Until the pointer is homed there's no telling where it is, so it's estimated to start in
+    /// the middle of the host framebuffer of the given size.
+    pub fn new(config: MouseConfig, bounds: PhysicalSize<u32>) -> Self {
         Self {
             report: hid::MouseReport {
                 x: 0,
@@ -22,38 +274,549 @@ impl Mouse {
                 wheel: 0,
             },
             report_buf: vec![0; 5],
-            active_touch: None,
+            touches: HashMap::new(),
+            button_touches: HashMap::new(),
+            scroll_remainder: (0.0, 0.0),
+            motion_remainder: (0.0, 0.0),
+            last_motion: None,
+            gesture: None,
+            session: None,
+            calibrating: false,
+            estimated_cursor: (bounds.width as f64 / 2.0, bounds.height as f64 / 2.0),
+            bounds,
+            config,
+            features: hid::MouseFeatureReport::default(),
+            captured: false,
         }
     }
 
-    pub fn handle_touch(&mut self, touch: Touch, x: &GadgetProcess) {
+    /// Sets the size of the host framebuffer, which the estimated cursor is kept within.
+    pub fn set_bounds(&mut self, bounds: PhysicalSize<u32>) {
+        self.bounds = bounds;
+        let (x, y) = self.estimated_cursor;
+        self.estimated_cursor = (x.min(bounds.width as f64), y.min(bounds.height as f64));
+    }
+
+    /// Where the host pointer is estimated to be, in framebuffer pixels. Only a guess until it's
+    /// resynchronised by homing, which a three-finger long-press does.
+    pub fn estimated_cursor(&self) -> PhysicalPosition<f64> {
+        PhysicalPosition::new(self.estimated_cursor.0, self.estimated_cursor.1)
+    }
+
+    /// Where the calibration wizard expects the host pointer to be, going by the current scaling,
+    /// while it's waiting for the user to tap where it actually is.
+    pub fn calibration_target(&self) -> Option<PhysicalPosition<f64>> {
+        self.calibrating.then(|| self.estimated_cursor())
+    }
+
+    /// Called when the host changes the Resolution Multiplier feature report.
+    pub fn set_features(&mut self, features: hid::MouseFeatureReport) {
+        self.features = features;
+        self.scroll_remainder = (0.0, 0.0);
+    }
+
+    pub fn handle_touch(&mut self, touch: Touch, region: TouchRegion, kb: &mut Keyboard, gadget: &GadgetProcess) {
+        // Touches stick to whichever region they started in, even if they wander out of it.
+        if let Some(&bit) = self.button_touches.get(&touch.id) {
+            if matches!(touch.phase, TouchPhase::Ended | TouchPhase::Cancelled) {
+                self.button_touches.remove(&touch.id);
+                self.set_button(bit, false, gadget);
+            }
+            return;
+        }
+        if touch.phase == TouchPhase::Started {
+            if self.calibrating {
+                self.finish_calibration(touch.location);
+                return;
+            }
+            match region {
+                // Whether touches on the mirrored display count is up to the layout.
+                TouchRegion::Display | TouchRegion::Trackpad => {}
+                TouchRegion::TrackpadButton(button) => {
+                    if let Some(bit) = button.bit() {
+                        self.button_touches.insert(touch.id, bit);
+                        self.set_button(bit, true, gadget);
+                    }
+                    return;
+                }
+            }
+        } else if !self.touches.contains_key(&touch.id) {
+            return;
+        }
+
         match touch.phase {
             TouchPhase::Started => {
-                if self.active_touch.is_none() {
-                    self.active_touch = Some((touch.id, touch.location));
+                self.touches.insert(touch.id, touch.location);
+                self.scroll_remainder = (0.0, 0.0);
+                self.motion_remainder = (0.0, 0.0);
+                self.last_motion = None;
+                self.end_gesture(kb, gadget);
+                if let Some((distance, midpoint)) = self.two_finger_geometry() {
+                    self.gesture = Some(TwoFingerGesture::Undecided { distance, midpoint });
+                }
+                let session = self.session.get_or_insert_with(|| TouchSession {
+                    started: Instant::now(),
+                    max_fingers: 0,
+                    travel: 0.0,
+                    held: None,
+                });
+                session.max_fingers = session.max_fingers.max(self.touches.len());
+                if self.config.pointing == Pointing::Homing && self.touches.len() == 1 {
+                    self.move_to(touch.location, gadget);
                 }
             }
             TouchPhase::Cancelled | TouchPhase::Ended => {
-                if let Some((id, _)) = self.active_touch {
-                    if id == touch.id {
-                        self.active_touch = None;
-                    }
+                self.touches.remove(&touch.id);
+                self.scroll_remainder = (0.0, 0.0);
+                self.end_gesture(kb, gadget);
+                if self.touches.is_empty() {
+                    self.end_session(touch.phase == TouchPhase::Cancelled, gadget);
                 }
             }
             TouchPhase::Moved => {
-                if let Some((id, old_pos)) = self.active_touch {
-                    if id == touch.id {
-                        self.report.x = (touch.location.x - old_pos.x) as i8;
-                        self.report.y = (touch.location.y - old_pos.y) as i8;
-                        ssmarshal::serialize(self.report_buf.as_mut_slice(), &self.report)
-                            .expect("report serialization");
-                        // self.fifo
-                        //     .write_all(&self.report_buf)
-                        //     .expect("mouse report write failed");
-                        self.active_touch = Some((id, touch.location));
+                if let Some(old_pos) = self.touches.insert(touch.id, touch.location) {
+                    let dx = touch.location.x - old_pos.x;
+                    let dy = touch.location.y - old_pos.y;
+                    self.track_session_motion(dx, dy, gadget);
+                    match self.touches.len() {
+                        1 => self.send_touch_motion(dx, dy, gadget),
+                        2 => self.handle_two_finger_motion(dx, dy, kb, gadget),
+                        _ => {}
                     }
                 }
             }
         }
     }
+
+    fn handle_two_finger_motion(&mut self, dx: f64, dy: f64, kb: &mut Keyboard, gadget: &GadgetProcess) {
+        let Some((distance, midpoint)) = self.two_finger_geometry() else {
+            return;
+        };
+        match self.gesture {
+            Some(TwoFingerGesture::Undecided { distance: start_distance, midpoint: start_midpoint }) => {
+                let pinch_allowed = self.config.pinch_zoom != PinchZoom::Off;
+                let gesture =
+                    TwoFingerGesture::decide((start_distance, start_midpoint), (distance, midpoint), pinch_allowed);
+                if let Some(TwoFingerGesture::Pinch { .. }) = gesture {
+                    kb.hold_modifiers(self.config.pinch_zoom.modifiers(), gadget);
+                }
+                if gesture.is_some() {
+                    self.gesture = gesture;
+                }
+            }
+            Some(TwoFingerGesture::Scroll) => {
+                // Wheel is positive when scrolling up, pan is positive when scrolling right. With
+                // natural scrolling the content follows the fingers, so dragging down scrolls up.
+                // Each finger reports its own movement, so halve the deltas to get the movement of
+                // the midpoint between them.
+                let direction = if self.config.natural_scroll { 1.0 } else { -1.0 };
+                let scale = direction * self.config.scroll_speed / SCROLL_STEP;
+                self.send_scroll(-dx / 2.0 * scale, dy / 2.0 * scale, gadget)
+            }
+            Some(TwoFingerGesture::Pinch { distance: last_distance }) => {
+                self.gesture = Some(TwoFingerGesture::Pinch { distance });
+                self.send_scroll(0.0, pinch_notches(last_distance, distance), gadget);
+            }
+            None => {}
+        }
+    }
+
+    /// Starts a long-press drag when a single finger that was held still starts moving.
+    fn track_session_motion(&mut self, dx: f64, dy: f64, gadget: &GadgetProcess) {
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        let was_still = session.travel <= TAP_SLOP;
+        session.travel += dx.hypot(dy);
+        if was_still
+            && session.travel > TAP_SLOP
+            && session.max_fingers == 1
+            && session.started.elapsed() >= LONG_PRESS_TIMEOUT
+        {
+            if let Some(bit) = self.config.bindings.long_press.bit() {
+                session.held = Some(bit);
+                self.set_button(bit, true, gadget);
+            }
+        }
+    }
+
+    /// Called once every finger has been lifted, to click for taps and long-presses and release
+    /// any button held for a drag.
+    fn end_session(&mut self, cancelled: bool, gadget: &GadgetProcess) {
+        let Some(session) = self.session.take() else {
+            return;
+        };
+        if let Some(bit) = session.held {
+            self.set_button(bit, false, gadget);
+            return;
+        }
+        if cancelled || session.travel > TAP_SLOP {
+            return;
+        }
+        let elapsed = session.started.elapsed();
+        // Fingers that went on to pan the view arrive cancelled, so this only homes the pointer
+        // while the view stayed put.
+        if elapsed >= LONG_PRESS_TIMEOUT && session.max_fingers == 3 {
+            self.home(gadget);
+            return;
+        }
+        let button = if elapsed < TAP_TIMEOUT {
+            self.config.bindings.taps.get(session.max_fingers - 1).copied()
+        } else if elapsed >= LONG_PRESS_TIMEOUT && session.max_fingers == 1 {
+            Some(self.config.bindings.long_press)
+        } else if elapsed >= LONG_PRESS_TIMEOUT && session.max_fingers == 2 {
+            Some(self.config.bindings.two_finger_long_press)
+        } else {
+            None
+        };
+        if let Some(bit) = button.and_then(TouchButton::bit) {
+            self.set_button(bit, true, gadget);
+            self.set_button(bit, false, gadget);
+        }
+    }
+
+    /// Returns the distance between the two fingers and their midpoint, if exactly two are down.
+    fn two_finger_geometry(&self) -> Option<(f64, (f64, f64))> {
+        let mut touches = self.touches.values();
+        match (touches.next(), touches.next(), touches.next()) {
+            (Some(a), Some(b), None) => Some((
+                (a.x - b.x).hypot(a.y - b.y),
+                ((a.x + b.x) / 2.0, (a.y + b.y) / 2.0),
+            )),
+            _ => None,
+        }
+    }
+
+    fn end_gesture(&mut self, kb: &mut Keyboard, gadget: &GadgetProcess) {
+        if let Some(TwoFingerGesture::Pinch { .. }) = self.gesture.take() {
+            kb.release_modifiers(self.config.pinch_zoom.modifiers(), gadget);
+        }
+    }
+
+    pub fn is_captured(&self) -> bool {
+        self.captured
+    }
+
+    /// Grabs and hides the local cursor so that a physical mouse or trackpad drives the host
+    /// pointer, or hands it back to the local desktop.
+    pub fn set_captured(&mut self, window: &Window, captured: bool) {
+        if self.captured == captured {
+            return;
+        }
+        if captured {
+            // Not every platform supports locking the pointer in place, confining it to the window
+            // is good enough since only the raw device motion is forwarded.
+            if let Err(err) = window
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+            {
+                warn!("failed to grab cursor: {}", err);
+                return;
+            }
+        } else if let Err(err) = window.set_cursor_grab(CursorGrabMode::None) {
+            warn!("failed to release cursor: {}", err);
+        }
+        window.set_cursor_visible(!captured);
+        self.captured = captured;
+    }
+
+    pub fn handle_button(&mut self, button: MouseButton, state: ElementState, gadget: &GadgetProcess) {
+        let bit = match button {
+            MouseButton::Left => 0,
+            MouseButton::Right => 1,
+            MouseButton::Middle => 2,
+            MouseButton::Back => 3,
+            MouseButton::Forward => 4,
+            // Platforms number the remaining buttons differently, so there's no telling which of
+            // the report's spare bits they'd belong in.
+            MouseButton::Other(_) => return,
+        };
+        self.set_button(bit, state == ElementState::Pressed, gadget);
+    }
+
+    fn set_button(&mut self, bit: u8, pressed: bool, gadget: &GadgetProcess) {
+        if pressed {
+            self.report.buttons |= 1 << bit;
+        } else {
+            self.report.buttons &= !(1 << bit);
+        }
+        self.report.x = 0;
+        self.report.y = 0;
+        self.report.wheel = 0;
+        self.report.pan = 0;
+        self.send_report(gadget);
+    }
+
+    /// Forwards raw relative motion from a local mouse or trackpad. Reports only carry whole
+    /// counts, so the fractional part is kept for the next call.
+    pub fn handle_motion(&mut self, delta: (f64, f64), gadget: &GadgetProcess) {
+        let (steps, (x, y)) = carry_motion(&mut self.motion_remainder, delta);
+        for _ in 0..steps {
+            self.send_motion(x, y, gadget);
+        }
+    }
+
+    pub fn handle_wheel(&mut self, delta: MouseScrollDelta, gadget: &GadgetProcess) {
+        // winit deltas are positive when the content should move right/down, i.e. when scrolling
+        // left/up.
+        match delta {
+            MouseScrollDelta::LineDelta(x, y) => self.send_scroll(-x as f64, y as f64, gadget),
+            MouseScrollDelta::PixelDelta(pos) => {
+                self.send_scroll(-pos.x / SCROLL_STEP, pos.y / SCROLL_STEP, gadget)
+            }
+        }
+    }
+
+    /// Accelerates a single-finger touch delta according to the configured profile and sends it.
+    fn send_touch_motion(&mut self, dx: f64, dy: f64, gadget: &GadgetProcess) {
+        let now = Instant::now();
+        let speed = match self.last_motion.replace(now) {
+            Some(last) => {
+                let elapsed_ms = (now - last).as_secs_f64() * 1000.0;
+                dx.hypot(dy) / elapsed_ms.max(1.0)
+            }
+            None => 0.0,
+        };
+        let (factor_x, factor_y) = match self.config.pointing {
+            Pointing::Relative => {
+                let factor = self.config.sensitivity * self.config.accel_profile.factor(speed);
+                (factor, factor)
+            }
+            // The pointer has to keep tracking the finger, so no acceleration.
+            Pointing::Homing => self.config.homing_scale,
+        };
+
+        self.handle_motion((dx * factor_x, dy * factor_y), gadget);
+    }
+
+    /// Moves the host pointer to a framebuffer position by homing it in the top-left corner first.
+    fn move_to(&mut self, pos: PhysicalPosition<f64>, gadget: &GadgetProcess) {
+        let (scale_x, scale_y) = self.config.homing_scale;
+        self.home(gadget);
+        self.move_by_steps(pos.x * scale_x, pos.y * scale_y, gadget);
+    }
+
+    fn home(&mut self, gadget: &GadgetProcess) {
+        for _ in 0..HOMING_SLAM_REPORTS {
+            self.send_motion(-(i8::MAX as f64), -(i8::MAX as f64), gadget);
+        }
+        self.motion_remainder = (0.0, 0.0);
+        self.estimated_cursor = (0.0, 0.0);
+    }
+
+    fn move_by_steps(&mut self, dx: f64, dy: f64, gadget: &GadgetProcess) {
+        for (x, y) in homing_steps(dx, dy) {
+            self.send_motion(x, y, gadget);
+        }
+    }
+
+    /// Starts the calibration wizard for [`Pointing::Homing`]. The host pointer is homed and then
+    /// moved a known number of counts, the next tap tells where it ended up in framebuffer pixels.
+    pub fn start_calibration(&mut self, gadget: &GadgetProcess) {
+        info!("calibrating pointer scaling, tap where the host pointer is now");
+        self.home(gadget);
+        self.move_by_steps(CALIBRATION_COUNTS, CALIBRATION_COUNTS, gadget);
+        self.calibrating = true;
+    }
+
+    fn finish_calibration(&mut self, pos: PhysicalPosition<f64>) {
+        self.calibrating = false;
+        if pos.x < 1.0 || pos.y < 1.0 {
+            warn!("calibration tap too close to the corner, keeping the previous scaling");
+            return;
+        }
+        self.config.homing_scale = (CALIBRATION_COUNTS / pos.x, CALIBRATION_COUNTS / pos.y);
+        info!(
+            "pointer calibrated, pass --homing-scale {:.3},{:.3} to keep it",
+            self.config.homing_scale.0, self.config.homing_scale.1
+        );
+    }
+
+    /// Sends whole counts of motion, at most [`i8::MAX`] along either axis.
+    fn send_motion(&mut self, dx: f64, dy: f64, gadget: &GadgetProcess) {
+        if dx == 0.0 && dy == 0.0 {
+            return;
+        }
+        self.report.x = dx as i8;
+        self.report.y = dy as i8;
+        let (x, y) = &mut self.estimated_cursor;
+        let (scale_x, scale_y) = self.config.homing_scale;
+        *x = (*x + self.report.x as f64 / scale_x).clamp(0.0, self.bounds.width as f64);
+        *y = (*y + self.report.y as f64 / scale_y).clamp(0.0, self.bounds.height as f64);
+        self.report.wheel = 0;
+        self.report.pan = 0;
+        self.send_report(gadget);
+    }
+
+    /// Scrolls by the given number of notches, which may be fractional.
+    fn send_scroll(&mut self, pan_notches: f64, wheel_notches: f64, gadget: &GadgetProcess) {
+        let multiplier = |hires| if hires { hid::MOUSE_HIRES_MULTIPLIER as f64 } else { 1.0 };
+        let (pan, wheel) = &mut self.scroll_remainder;
+        *pan += pan_notches * multiplier(self.features.pan_hires);
+        *wheel += wheel_notches * multiplier(self.features.wheel_hires);
+
+        let (pan_units, wheel_units) = (pan.trunc(), wheel.trunc());
+        if pan_units == 0.0 && wheel_units == 0.0 {
+            return;
+        }
+        *pan -= pan_units;
+        *wheel -= wheel_units;
+
+        self.report.x = 0;
+        self.report.y = 0;
+        self.report.pan = pan_units as i8;
+        self.report.wheel = wheel_units as i8;
+        self.send_report(gadget);
+    }
+
+    fn send_report(&mut self, gadget: &GadgetProcess) {
+        ssmarshal::serialize(self.report_buf.as_mut_slice(), &self.report)
+            .expect("report serialization");
+        let mut report = [0; 5];
+        report.copy_from_slice(&self.report_buf);
+        gadget.send(IpcCommand::MouseReport(report)).unwrap();
+    }
+}
+
+/// Splits a move into whole count steps of at most [`HOMING_STEP`] along either axis.
+fn homing_steps(dx: f64, dy: f64) -> Vec<(f64, f64)> {
+    let steps = (dx.abs().max(dy.abs()) / HOMING_STEP).ceil();
+    let (mut sent_x, mut sent_y) = (0.0, 0.0);
+    (1..=steps as usize)
+        .map(|step| {
+            // Work from the running total so rounding doesn't accumulate over many steps.
+            let (x, y) = ((dx * step as f64 / steps).round(), (dy * step as f64 / steps).round());
+            let delta = (x - sent_x, y - sent_y);
+            (sent_x, sent_y) = (x, y);
+            delta
+        })
+        .collect()
+}
+
+/// Adds motion to what's carried over and takes the whole counts out of it, as a number of equal
+/// steps that each fit in a report. Fast flicks can easily exceed what fits in a single one, and
+/// what doesn't divide evenly into the steps is carried too.
+fn carry_motion(remainder: &mut (f64, f64), (dx, dy): (f64, f64)) -> (usize, (f64, f64)) {
+    let (rx, ry) = remainder;
+    *rx += dx;
+    *ry += dy;
+    let steps = (rx.trunc().abs().max(ry.trunc().abs()) / i8::MAX as f64).ceil();
+    if steps == 0.0 {
+        return (0, (0.0, 0.0));
+    }
+    let (x, y) = ((*rx / steps).trunc(), (*ry / steps).trunc());
+    *rx -= x * steps;
+    *ry -= y * steps;
+    (steps as usize, (x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MouseConfig {
+        MouseConfig {
+            natural_scroll: false,
+            scroll_speed: 1.0,
+            accel_profile: AccelProfile::default(),
+            sensitivity: 1.0,
+            pinch_zoom: PinchZoom::default(),
+            bindings: BindingProfile::default().bindings(),
+            pointing: Pointing::default(),
+            homing_scale: (1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn cursor_starts_in_the_middle() {
+        let mut mouse = Mouse::new(config(), PhysicalSize::new(1920, 1080));
+        assert_eq!(mouse.estimated_cursor(), PhysicalPosition::new(960.0, 540.0));
+
+        // A smaller mode keeps the estimate on screen.
+        mouse.set_bounds(PhysicalSize::new(800, 600));
+        assert_eq!(mouse.estimated_cursor(), PhysicalPosition::new(800.0, 540.0));
+    }
+
+    #[test]
+    fn fractional_motion_is_carried() {
+        let mut remainder = (0.0, 0.0);
+        assert_eq!(carry_motion(&mut remainder, (0.4, -0.4)), (0, (0.0, 0.0)));
+        assert_eq!(carry_motion(&mut remainder, (0.4, -0.4)), (0, (0.0, 0.0)));
+        // Only once a whole count has built up does any motion go out, the rest stays behind.
+        let (steps, (x, y)) = carry_motion(&mut remainder, (0.4, -0.4));
+        assert_eq!((steps, x, y), (1, 1.0, -1.0));
+        assert!((remainder.0 - 0.2).abs() < 1e-9 && (remainder.1 + 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fast_motion_is_split_into_reports() {
+        let mut remainder = (0.0, 0.0);
+        // 300 counts don't fit in one report, three steps of 100 do.
+        assert_eq!(carry_motion(&mut remainder, (300.0, -30.0)), (3, (100.0, -10.0)));
+        assert_eq!(remainder, (0.0, 0.0));
+        // What doesn't divide evenly is carried.
+        assert_eq!(carry_motion(&mut remainder, (256.0, 0.0)), (3, (85.0, 0.0)));
+        assert_eq!(remainder, (1.0, 0.0));
+    }
+
+    #[test]
+    fn acceleration_curves() {
+        for speed in [0.0, 0.5, 5.0] {
+            assert_eq!(AccelProfile::Flat.factor(speed), 1.0);
+        }
+
+        assert_eq!(AccelProfile::Linear.factor(0.0), 1.0);
+        assert!((AccelProfile::Linear.factor(2.5) - 3.0).abs() < 1e-9);
+
+        // Slow movement stays one to one, then ramps up and levels off.
+        assert_eq!(AccelProfile::Adaptive.factor(ADAPTIVE_THRESHOLD / 2.0), 1.0);
+        assert_eq!(AccelProfile::Adaptive.factor(ADAPTIVE_THRESHOLD), 1.0);
+        let faster = AccelProfile::Adaptive.factor(ADAPTIVE_THRESHOLD + 1.0);
+        assert!((faster - (1.0 + ADAPTIVE_INCLINE)).abs() < 1e-9);
+        assert_eq!(AccelProfile::Adaptive.factor(100.0), ADAPTIVE_MAX);
+    }
+
+    #[test]
+    fn pinch_needs_the_threshold() {
+        let start = (100.0, (50.0, 50.0));
+        let decide = |distance, midpoint, pinch_allowed| {
+            TwoFingerGesture::decide(start, (distance, midpoint), pinch_allowed)
+        };
+        assert_eq!(decide(100.0 + PINCH_THRESHOLD / 2.0, (50.0, 50.0), true), None);
+        assert_eq!(
+            decide(100.0 + PINCH_THRESHOLD + 1.0, (50.0, 50.0), true),
+            Some(TwoFingerGesture::Pinch { distance: 100.0 + PINCH_THRESHOLD + 1.0 })
+        );
+        // Fingers moving together scroll, even if their distance drifts a little.
+        assert_eq!(decide(110.0, (50.0, 70.0), true), Some(TwoFingerGesture::Scroll));
+        // Without pinch zoom, a pinch is left undecided unless it also moves.
+        assert_eq!(decide(200.0, (50.0, 50.0), false), None);
+        assert_eq!(decide(200.0, (50.0, 70.0), false), Some(TwoFingerGesture::Scroll));
+    }
+
+    #[test]
+    fn pinch_notches_per_doubling() {
+        assert_eq!(pinch_notches(100.0, 200.0), PINCH_NOTCHES_PER_DOUBLING);
+        assert_eq!(pinch_notches(200.0, 100.0), -PINCH_NOTCHES_PER_DOUBLING);
+        assert_eq!(pinch_notches(100.0, 100.0), 0.0);
+        // Fingers landing on top of each other don't divide by zero.
+        assert!(pinch_notches(0.0, 10.0).is_finite());
+    }
+
+    #[test]
+    fn homing_steps_add_up() {
+        assert!(homing_steps(0.0, 0.0).is_empty());
+
+        let steps = homing_steps(100.0, -30.5);
+        assert_eq!(steps.len(), (100.0 / HOMING_STEP).ceil() as usize);
+        assert!(steps.iter().all(|(x, y)| x.abs() <= HOMING_STEP && y.abs() <= HOMING_STEP));
+        assert!(steps.iter().all(|(x, y)| x.fract() == 0.0 && y.fract() == 0.0));
+        let total = steps.iter().fold((0.0, 0.0), |(tx, ty), (x, y)| (tx + x, ty + y));
+        assert_eq!(total, (100.0, -31.0));
+    }
+
+    #[test]
+    fn homing_slam_crosses_4k() {
+        assert!(HOMING_SLAM_REPORTS as f64 * i8::MAX as f64 >= 3840.0);
+    }
 }
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::{error, info};
+use winit::event_loop::EventLoopProxy;
+
+use crate::display::Rotation;
+use crate::AppEvent;
+
+/// How often the accelerometer is read.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How far the device has to be tilted up from lying flat, in degrees, before it counts as being
+/// held some way up. The same as iio-sensor-proxy.
+const TILT_THRESHOLD: f64 = 35.0;
+
+const IDENTITY: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// An IIO accelerometer in sysfs, which unprivileged users can read.
+pub struct Accelerometer {
+    dir: PathBuf,
+    // Turns the sensor's axes into the panel's.
+    mount_matrix: [[f64; 3]; 3],
+}
+
+impl Accelerometer {
+    /// Finds the first accelerometer under the given sysfs root, if the device has one.
+    pub fn find(sysfs_root: &Path) -> anyhow::Result<Option<Self>> {
+        let devices_dir = sysfs_root.join("bus/iio/devices");
+        for entry in std::fs::read_dir(&devices_dir)
+            .with_context(|| format!("failed to list {}", devices_dir.display()))?
+        {
+            let dir = entry?.path();
+            if !dir.join("in_accel_x_raw").exists() {
+                continue;
+            }
+            let mount_matrix = ["in_accel_mount_matrix", "mount_matrix"]
+                .iter()
+                .find_map(|name| std::fs::read_to_string(dir.join(name)).ok())
+                .map(|matrix| parse_mount_matrix(&matrix))
+                .transpose()?
+                .unwrap_or(IDENTITY);
+            return Ok(Some(Self { dir, mount_matrix }));
+        }
+        Ok(None)
+    }
+
+    /// Gravity along the panel's x, y and z axes, in the sensor's units.
+    fn read(&self) -> anyhow::Result<[f64; 3]> {
+        let mut raw = [0.0; 3];
+        for (value, axis) in raw.iter_mut().zip(["x", "y", "z"]) {
+            let path = self.dir.join(format!("in_accel_{}_raw", axis));
+            *value = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?
+                .trim()
+                .parse()
+                .with_context(|| format!("failed to parse {}", path.display()))?;
+        }
+        Ok(self.mount_matrix.map(|row| row.iter().zip(raw).map(|(m, v)| m * v).sum()))
+    }
+}
+
+/// Parses a mount matrix, written as rows separated by semicolons.
+fn parse_mount_matrix(matrix: &str) -> anyhow::Result<[[f64; 3]; 3]> {
+    let mut parsed = [[0.0; 3]; 3];
+    let rows: Vec<&str> = matrix.trim().split(';').collect();
+    if rows.len() != 3 {
+        anyhow::bail!("mount matrix {:?} doesn't have 3 rows", matrix);
+    }
+    for (parsed, row) in parsed.iter_mut().zip(rows) {
+        let values: Vec<&str> = row.split(',').collect();
+        if values.len() != 3 {
+            anyhow::bail!("mount matrix {:?} doesn't have 3 columns", matrix);
+        }
+        for (parsed, value) in parsed.iter_mut().zip(values) {
+            *parsed = value.trim().parse().context("failed to parse mount matrix")?;
+        }
+    }
+    Ok(parsed)
+}
+
+/// How the mirrored display has to be turned to stay upright, given gravity along the panel's axes.
+/// Lying about flat keeps the previous rotation. Gravity reads negative along y when the device is
+/// held normally, as with iio-sensor-proxy.
+fn rotation_for(gravity: [f64; 3], previous: Rotation) -> Rotation {
+    let [x, y, z] = gravity;
+    let x_tilt = x.atan2((y * y + z * z).sqrt()).to_degrees();
+    let y_tilt = y.atan2((x * x + z * z).sqrt()).to_degrees();
+    if x_tilt.abs() > TILT_THRESHOLD && x_tilt.abs() >= y_tilt.abs() {
+        // The left edge up means the device was turned clockwise, so the picture turns back.
+        if x_tilt > 0.0 { Rotation::R270 } else { Rotation::R90 }
+    } else if y_tilt.abs() > TILT_THRESHOLD {
+        if y_tilt > 0.0 { Rotation::R180 } else { Rotation::R0 }
+    } else {
+        previous
+    }
+}
+
+/// Reads the accelerometer in the background, sending an event whenever the device is turned a
+/// different way up, starting from `rotation`.
+pub fn watch(events: EventLoopProxy<AppEvent>, mut rotation: Rotation) -> anyhow::Result<()> {
+    let accelerometer = Accelerometer::find(Path::new("/sys"))?.context("no accelerometer found")?;
+    info!("following device orientation from {}", accelerometer.dir.display());
+    std::thread::spawn(move || loop {
+        let gravity = match accelerometer.read() {
+            Ok(gravity) => gravity,
+            Err(err) => {
+                error!("reading accelerometer failed: {}", err);
+                return;
+            }
+        };
+        let now = rotation_for(gravity, rotation);
+        if now != rotation {
+            rotation = now;
+            if events.send_event(AppEvent::OrientationChanged(rotation)).is_err() {
+                return;
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_follows_gravity() {
+        assert_eq!(rotation_for([0.0, -1.0, 0.2], Rotation::R90), Rotation::R0);
+        assert_eq!(rotation_for([0.0, 1.0, 0.2], Rotation::R0), Rotation::R180);
+        assert_eq!(rotation_for([1.0, -0.2, 0.2], Rotation::R0), Rotation::R270);
+        assert_eq!(rotation_for([-1.0, -0.2, 0.2], Rotation::R0), Rotation::R90);
+    }
+
+    #[test]
+    fn lying_flat_keeps_rotation() {
+        assert_eq!(rotation_for([0.1, -0.3, -1.0], Rotation::R90), Rotation::R90);
+    }
+
+    #[test]
+    fn mount_matrix_parses() {
+        assert_eq!(parse_mount_matrix("1, 0, 0; 0, 1, 0; 0, 0, 1\n").unwrap(), IDENTITY);
+        assert_eq!(parse_mount_matrix("0, -1, 0; 1, 0, 0; 0, 0, 1").unwrap()[0], [0.0, -1.0, 0.0]);
+        assert!(parse_mount_matrix("1, 0, 0; 0, 1, 0").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{hid, touchscreen};
+use winit::dpi::PhysicalSize;
+use winit::event::{Touch, TouchPhase};
+use crate::gadget::{GadgetProcess, IpcCommand};
+
+/// Forwards touches as a stylus, so the device can be used as a graphics tablet for the host.
+///
+/// winit doesn't tell styluses and fingers apart, nor does it expose hovering, pen buttons or
+/// which way a stylus is tilted. The barrel switch and eraser are described to the host, but are
+/// always reported released since there is nothing to drive them. The first touch is the pen, any
+/// others are ignored. The pen is brought into range just before it touches down and taken out of
+/// range right after it lifts, since hosts expect to see it arrive and leave with the tip up.
+pub struct Pen {
+    report: hid::PenReport,
+    pub report_buf: Vec<u8>,
+    pen: Option<u64>,
+}
+
+impl Pen {
+    pub fn new() -> Self {
+        Self {
+            report: hid::PenReport::default(),
+            report_buf: vec![0; hid::PenReport::LEN],
+            pen: None,
+        }
+    }
+
+    pub fn handle_touch(&mut self, touch: Touch, framebuffer_size: PhysicalSize<u32>, gadget: &GadgetProcess) {
+        if self.pen.is_none() && touch.phase == TouchPhase::Started {
+            self.pen = Some(touch.id);
+        }
+        if self.pen != Some(touch.id) {
+            return;
+        }
+
+        (self.report.x, self.report.y) = touchscreen::to_logical(touch.location, framebuffer_size);
+        match touch.phase {
+            TouchPhase::Started => {
+                self.report.in_range = true;
+                self.report.tip = false;
+                self.report.pressure = 0;
+                self.send_report(gadget);
+                self.report.tip = true;
+            }
+            TouchPhase::Moved => {}
+            TouchPhase::Ended | TouchPhase::Cancelled => {
+                self.report.tip = false;
+                self.report.pressure = 0;
+                self.send_report(gadget);
+                self.report.in_range = false;
+                self.send_report(gadget);
+                self.pen = None;
+                return;
+            }
+        }
+        self.report.pressure = match touch.force {
+            Some(force) => (force.normalized().clamp(0.0, 1.0) * hid::PEN_PRESSURE_MAX as f64) as u16,
+            // Without pressure data, report a firm press rather than none at all.
+            None => hid::PEN_PRESSURE_MAX / 2,
+        };
+        self.send_report(gadget);
+    }
+
+    fn send_report(&mut self, gadget: &GadgetProcess) {
+        ssmarshal::serialize(self.report_buf.as_mut_slice(), &self.report)
+            .expect("report serialization");
+        gadget.send(IpcCommand::PenReport(self.report_buf.clone())).unwrap();
+    }
+}
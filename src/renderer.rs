@@ -0,0 +1,578 @@
+use pixels::wgpu;
+use pixels::wgpu::util::DeviceExt;
+use pixels::Pixels;
+use crate::display::{Rotation, ScaleMode};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+
+/// Where the pixel buffer is drawn in the window: the buffer is turned by `rotation`, then pixel
+/// `p` of the turned buffer lands on window pixel `offset + p * scale`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewTransform {
+    pub scale: f64,
+    pub offset: (f64, f64),
+    pub rotation: Rotation,
+    pub buffer: PhysicalSize<u32>,
+}
+
+impl ViewTransform {
+    /// Scales the buffer into the surface according to `scale_mode` and then magnifies it by
+    /// `zoom`, preserving its aspect ratio. It's centred along each axis it fits in, and moved by
+    /// `pan` along those it overflows.
+    pub fn new(
+        buffer: PhysicalSize<u32>,
+        surface: PhysicalSize<u32>,
+        rotation: Rotation,
+        scale_mode: ScaleMode,
+        zoom: f64,
+        pan: (f64, f64),
+    ) -> Self {
+        let turned = rotation.apply(buffer);
+        let ratio_x = surface.width as f64 / turned.width as f64;
+        let ratio_y = surface.height as f64 / turned.height as f64;
+        let scale = match scale_mode {
+            ScaleMode::Fit => ratio_x.min(ratio_y),
+            ScaleMode::Fill => ratio_x.max(ratio_y),
+            ScaleMode::Integer => ratio_x.min(ratio_y).floor().max(1.0),
+            ScaleMode::OneToOne => 1.0,
+        } * zoom;
+        let offset = |surface: u32, extent: u32, pan: f64| {
+            let overflow = surface as f64 - extent as f64 * scale;
+            if overflow >= 0.0 {
+                overflow / 2.0
+            } else {
+                (overflow / 2.0 + pan).clamp(overflow, 0.0)
+            }
+        };
+        Self {
+            scale,
+            offset: (
+                offset(surface.width, turned.width, pan.0),
+                offset(surface.height, turned.height, pan.1),
+            ),
+            rotation,
+            buffer,
+        }
+    }
+
+    /// The pan that would give this transform's offset, i.e. a pan clamped to the overflow.
+    pub fn pan(&self, surface: PhysicalSize<u32>) -> (f64, f64) {
+        let (width, height) = self.extent();
+        let pan = |surface: u32, extent: f64, offset: f64| {
+            if extent > surface as f64 {
+                offset - (surface as f64 - extent) / 2.0
+            } else {
+                0.0
+            }
+        };
+        (pan(surface.width, width, self.offset.0), pan(surface.height, height, self.offset.1))
+    }
+
+    /// Whether the buffer overflows the surface, so there's something to pan to.
+    pub fn overflows(&self, surface: PhysicalSize<u32>) -> bool {
+        let (width, height) = self.extent();
+        width > surface.width as f64 || height > surface.height as f64
+    }
+
+    /// Size of the turned and scaled buffer in the window.
+    pub fn extent(&self) -> (f64, f64) {
+        let turned = self.rotation.apply(self.buffer);
+        (turned.width as f64 * self.scale, turned.height as f64 * self.scale)
+    }
+
+    /// The matrix moving the full-screen triangle's clip space -1..1 to where the buffer goes.
+    fn matrix(&self, surface: PhysicalSize<u32>) -> [f32; 16] {
+        let (surface_width, surface_height) = (surface.width.max(1) as f64, surface.height.max(1) as f64);
+        let (width, height) = self.extent();
+
+        // Turn it, then scale and move it. Clip space y points up, window y points down.
+        let (cos, sin) = match self.rotation {
+            Rotation::R0 => (1.0, 0.0),
+            Rotation::R90 => (0.0, 1.0),
+            Rotation::R180 => (-1.0, 0.0),
+            Rotation::R270 => (0.0, -1.0),
+        };
+        let sx = width / surface_width;
+        let sy = height / surface_height;
+        let tx = (self.offset.0 * 2.0 + width) / surface_width - 1.0;
+        let ty = 1.0 - (self.offset.1 * 2.0 + height) / surface_height;
+        #[rustfmt::skip]
+        let matrix = [
+            (sx * cos) as f32,  (-sy * sin) as f32, 0.0, 0.0,
+            (sx * sin) as f32,  (sy * cos) as f32,  0.0, 0.0,
+            0.0,                0.0,                1.0, 0.0,
+            tx as f32,          ty as f32,          0.0, 1.0,
+        ];
+        matrix
+    }
+
+    /// Undoes the transform for a window position, the inverse of what the renderer draws.
+    pub fn window_to_buffer(&self, pos: PhysicalPosition<f64>) -> PhysicalPosition<f64> {
+        let x = (pos.x - self.offset.0) / self.scale;
+        let y = (pos.y - self.offset.1) / self.scale;
+        let (width, height) = (self.buffer.width as f64, self.buffer.height as f64);
+        match self.rotation {
+            Rotation::R0 => PhysicalPosition::new(x, y),
+            Rotation::R90 => PhysicalPosition::new(y, height - x),
+            Rotation::R180 => PhysicalPosition::new(width - x, height - y),
+            Rotation::R270 => PhysicalPosition::new(width - y, x),
+        }
+    }
+}
+
+/// A rectangle of the pixel buffer, or of the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The smallest rect covering both.
+    pub fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Draws the pixel buffer into the window with an arbitrary [`ViewTransform`]. `pixels` only
+/// scales by whole numbers, which crops host modes larger than the window.
+///
+/// It keeps its own texture so that only the damaged part of the buffer is uploaded each frame,
+/// where `pixels` would upload all of it. It has to be recreated whenever the buffer is resized.
+///
+/// Solid rects can be drawn over it in window pixels, for things that shouldn't move with the view.
+pub struct Renderer {
+    texture: wgpu::Texture,
+    texture_size: PhysicalSize<u32>,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    render_pipeline: wgpu::RenderPipeline,
+    clip_rect: (u32, u32, u32, u32),
+    surface: PhysicalSize<u32>,
+    // One pixel per fill, in the frame's byte order.
+    palette: wgpu::Texture,
+    fill_bind_group: wgpu::BindGroup,
+    fill_pipeline: wgpu::RenderPipeline,
+    // Palette index and where it goes, already clamped to the surface.
+    fills: Vec<(u32, Rect)>,
+}
+
+/// How many rects [`Renderer::set_fills`] can draw.
+const MAX_FILLS: usize = 8;
+
+impl Renderer {
+    pub fn new(pixels: &Pixels, buffer: PhysicalSize<u32>) -> Self {
+        let device = pixels.device();
+        let module = device.create_shader_module(wgpu::include_wgsl!("shaders/view.wgsl"));
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("usb_kvm_renderer_texture"),
+            size: wgpu::Extent3d {
+                width: buffer.width,
+                height: buffer.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            // The frame is kept in the host's XRGB8888 byte order, so it can be copied straight in.
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("usb_kvm_renderer_sampler"),
+            // Host modes are often scaled down into the window, nearest neighbour would drop
+            // whole rows of text.
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let transform_bytes = matrix_bytes(&[0.0; 16]);
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("usb_kvm_renderer_matrix_uniform_buffer"),
+            contents: &transform_bytes,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("usb_kvm_renderer_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(transform_bytes.len() as u64),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("usb_kvm_renderer_bind_group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("usb_kvm_renderer_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("usb_kvm_renderer_pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &module,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: pixels.render_texture_format(),
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+
+        let fill_module = device.create_shader_module(wgpu::include_wgsl!("shaders/fill.wgsl"));
+        let palette = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("usb_kvm_renderer_palette"),
+            size: wgpu::Extent3d {
+                width: MAX_FILLS as u32,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let palette_view = palette.create_view(&wgpu::TextureViewDescriptor::default());
+        let fill_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("usb_kvm_renderer_fill_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            }],
+        });
+        let fill_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("usb_kvm_renderer_fill_bind_group"),
+            layout: &fill_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&palette_view),
+            }],
+        });
+        let fill_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("usb_kvm_renderer_fill_pipeline_layout"),
+            bind_group_layouts: &[&fill_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let fill_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("usb_kvm_renderer_fill_pipeline"),
+            layout: Some(&fill_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &fill_module,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &fill_module,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: pixels.render_texture_format(),
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+
+        Self {
+            texture,
+            texture_size: buffer,
+            uniform_buffer,
+            bind_group,
+            render_pipeline,
+            clip_rect: (0, 0, 0, 0),
+            surface: PhysicalSize::new(0, 0),
+            palette,
+            fill_bind_group,
+            fill_pipeline,
+            fills: Vec::new(),
+        }
+    }
+
+    /// Uploads a rect of the XRGB8888 pixel buffer to the texture.
+    pub fn upload(&self, pixels: &Pixels, frame: &[u8], rect: Rect) {
+        let bytes_per_row = self.texture_size.width * 4;
+        pixels.queue().write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: rect.x, y: rect.y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            frame,
+            wgpu::ImageDataLayout {
+                offset: (rect.y * bytes_per_row + rect.x * 4) as wgpu::BufferAddress,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: Some(rect.height),
+            },
+            wgpu::Extent3d {
+                width: rect.width,
+                height: rect.height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Updates where the buffer is drawn.
+    pub fn set_transform(&mut self, pixels: &Pixels, transform: ViewTransform, surface: PhysicalSize<u32>) {
+        pixels.queue().write_buffer(&self.uniform_buffer, 0, &matrix_bytes(&transform.matrix(surface)));
+
+        // Only draw within the part of the window the buffer covers.
+        let (surface_width, surface_height) = (surface.width.max(1) as f64, surface.height.max(1) as f64);
+        let (width, height) = transform.extent();
+        let left = transform.offset.0.clamp(0.0, surface_width);
+        let top = transform.offset.1.clamp(0.0, surface_height);
+        let right = (transform.offset.0 + width).clamp(0.0, surface_width);
+        let bottom = (transform.offset.1 + height).clamp(0.0, surface_height);
+        self.clip_rect = (left as u32, top as u32, (right - left) as u32, (bottom - top) as u32);
+        self.surface = surface;
+    }
+
+    /// Sets the rects drawn over the buffer, in window pixels and in order, each with a colour in
+    /// the frame's byte order. Only the first [`MAX_FILLS`] are drawn.
+    pub fn set_fills(&mut self, pixels: &Pixels, fills: &[(Rect, [u8; 4])]) {
+        let fills = &fills[..fills.len().min(MAX_FILLS)];
+        if !fills.is_empty() {
+            let colors: Vec<u8> = fills.iter().flat_map(|(_, color)| *color).collect();
+            pixels.queue().write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &self.palette,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &colors,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(colors.len() as u32),
+                    rows_per_image: Some(1),
+                },
+                wgpu::Extent3d {
+                    width: fills.len() as u32,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        // Scissor rects have to lie within the render target.
+        let (surface_width, surface_height) = (self.surface.width, self.surface.height);
+        self.fills = (0..)
+            .zip(fills)
+            .map(|(index, (rect, _))| {
+                let x = rect.x.min(surface_width);
+                let y = rect.y.min(surface_height);
+                let width = rect.width.min(surface_width - x);
+                let height = rect.height.min(surface_height - y);
+                (index, Rect { x, y, width, height })
+            })
+            .filter(|(_, rect)| rect.width > 0 && rect.height > 0)
+            .collect();
+    }
+
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, render_target: &wgpu::TextureView) {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("usb_kvm_renderer_render_pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: render_target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        let (x, y, width, height) = self.clip_rect;
+        if width > 0 && height > 0 {
+            rpass.set_pipeline(&self.render_pipeline);
+            rpass.set_bind_group(0, &self.bind_group, &[]);
+            rpass.set_scissor_rect(x, y, width, height);
+            rpass.draw(0..3, 0..1);
+        }
+
+        if self.fills.is_empty() {
+            return;
+        }
+        rpass.set_pipeline(&self.fill_pipeline);
+        rpass.set_bind_group(0, &self.fill_bind_group, &[]);
+        for &(index, rect) in &self.fills {
+            // The shader picks the palette colour by instance index.
+            rpass.set_scissor_rect(rect.x, rect.y, rect.width, rect.height);
+            rpass.draw(0..3, index..index + 1);
+        }
+    }
+}
+
+fn matrix_bytes(matrix: &[f32; 16]) -> Vec<u8> {
+    matrix.iter().flat_map(|v| v.to_ne_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROTATIONS: [Rotation; 4] = [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270];
+
+    fn assert_near(a: PhysicalPosition<f64>, b: PhysicalPosition<f64>) {
+        assert!((a.x - b.x).abs() < 1e-3 && (a.y - b.y).abs() < 1e-3, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn fit_letterboxes() {
+        let transform = ViewTransform::new(
+            PhysicalSize::new(100, 50),
+            PhysicalSize::new(200, 200),
+            Rotation::R0,
+            ScaleMode::Fit,
+            1.0,
+            (0.0, 0.0),
+        );
+        assert_eq!(transform.scale, 2.0);
+        assert_eq!(transform.offset, (0.0, 50.0));
+        assert!(!transform.overflows(PhysicalSize::new(200, 200)));
+        assert_near(transform.window_to_buffer(PhysicalPosition::new(0.0, 50.0)), PhysicalPosition::new(0.0, 0.0));
+        assert_near(transform.window_to_buffer(PhysicalPosition::new(200.0, 150.0)), PhysicalPosition::new(100.0, 50.0));
+    }
+
+    #[test]
+    fn quarter_turn_is_clockwise() {
+        let transform = ViewTransform::new(
+            PhysicalSize::new(100, 50),
+            PhysicalSize::new(50, 100),
+            Rotation::R90,
+            ScaleMode::Fit,
+            1.0,
+            (0.0, 0.0),
+        );
+        assert_eq!(transform.scale, 1.0);
+        // The buffer's top left corner ends up in the window's top right one.
+        assert_near(transform.window_to_buffer(PhysicalPosition::new(50.0, 0.0)), PhysicalPosition::new(0.0, 0.0));
+        assert_near(transform.window_to_buffer(PhysicalPosition::new(0.0, 0.0)), PhysicalPosition::new(0.0, 50.0));
+    }
+
+    #[test]
+    fn pan_is_clamped_to_overflow() {
+        let surface = PhysicalSize::new(200, 200);
+        let transform = ViewTransform::new(
+            PhysicalSize::new(400, 300),
+            surface,
+            Rotation::R0,
+            ScaleMode::OneToOne,
+            1.0,
+            (1000.0, -1000.0),
+        );
+        assert!(transform.overflows(surface));
+        assert_eq!(transform.offset, (0.0, -100.0));
+        assert_eq!(transform.pan(surface), (100.0, -50.0));
+    }
+
+    /// Where the renderer draws a buffer position, going through the same matrix as the shader.
+    fn render(transform: &ViewTransform, surface: PhysicalSize<u32>, pos: PhysicalPosition<f64>) -> PhysicalPosition<f64> {
+        // The shader samples texture coordinate (p + 1) / 2, flipped vertically, at clip position p.
+        let p = (
+            pos.x / transform.buffer.width as f64 * 2.0 - 1.0,
+            1.0 - pos.y / transform.buffer.height as f64 * 2.0,
+        );
+        let m = transform.matrix(surface).map(|v| v as f64);
+        let clip = (m[0] * p.0 + m[4] * p.1 + m[12], m[1] * p.0 + m[5] * p.1 + m[13]);
+        PhysicalPosition::new(
+            (clip.0 + 1.0) / 2.0 * surface.width as f64,
+            (1.0 - clip.1) / 2.0 * surface.height as f64,
+        )
+    }
+
+    #[test]
+    fn window_to_buffer_undoes_rendering() {
+        let surface = PhysicalSize::new(1000, 700);
+        let modes = [ScaleMode::Fit, ScaleMode::Fill, ScaleMode::Integer, ScaleMode::OneToOne];
+        for rotation in ROTATIONS {
+            for scale_mode in modes {
+                let transform = ViewTransform::new(
+                    PhysicalSize::new(640, 480),
+                    surface,
+                    rotation,
+                    scale_mode,
+                    1.5,
+                    (30.0, -20.0),
+                );
+                for pos in [(0.0, 0.0), (640.0, 480.0), (123.5, 456.25)] {
+                    let pos = PhysicalPosition::new(pos.0, pos.1);
+                    assert_near(transform.window_to_buffer(render(&transform, surface, pos)), pos);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,23 @@
+// Fills the scissor rect with the palette colour at the instance index, by drawing a triangle
+// covering the whole target like view.wgsl does.
+
+struct VertexOutput {
+    @location(0) @interpolate(flat) color: u32,
+    @builtin(position) position: vec4<f32>,
+}
+
+@group(0) @binding(0) var palette: texture_2d<f32>;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32, @builtin(instance_index) color: u32) -> VertexOutput {
+    var out: VertexOutput;
+    out.color = color;
+    out.position = vec4<f32>(f32(index & 1u) * 4.0 - 1.0, f32(index >> 1u) * 4.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(@location(0) @interpolate(flat) color: u32) -> @location(0) vec4<f32> {
+    // Colours are in the frame's byte order, the fourth byte is padding.
+    return vec4<f32>(textureLoad(palette, vec2<i32>(i32(color), 0), 0).rgb, 1.0);
+}
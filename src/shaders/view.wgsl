@@ -0,0 +1,27 @@
+// Draws the pixel buffer on a single triangle covering clip space -1..1, which the transform then
+// moves to wherever the buffer goes in the window.
+
+struct VertexOutput {
+    @location(0) tex_coord: vec2<f32>,
+    @builtin(position) position: vec4<f32>,
+}
+
+@group(0) @binding(0) var frame: texture_2d<f32>;
+@group(0) @binding(1) var frame_sampler: sampler;
+@group(0) @binding(2) var<uniform> view_transform: mat4x4<f32>;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // (-1, -1), (3, -1) and (-1, 3), see https://github.com/parasyte/pixels/issues/180
+    let position = vec2<f32>(f32(index & 1u) * 4.0 - 1.0, f32(index >> 1u) * 4.0 - 1.0);
+    var out: VertexOutput;
+    out.tex_coord = fma(position, vec2<f32>(0.5, -0.5), vec2<f32>(0.5, 0.5));
+    out.position = view_transform * vec4<f32>(position, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(@location(0) tex_coord: vec2<f32>) -> @location(0) vec4<f32> {
+    // The frame's fourth byte is padding, not alpha.
+    return vec4<f32>(textureSample(frame, frame_sampler, tex_coord).rgb, 1.0);
+}
@@ -0,0 +1,125 @@
+use crate::hid;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{Touch, TouchPhase};
+use crate::gadget::{GadgetProcess, IpcCommand};
+
+/// Forwards every touch point to the host as a native multi-touch digitizer, so hosts can do
+/// their own gesture recognition.
+pub struct Touchscreen {
+    report: hid::TouchscreenReport,
+    pub report_buf: Vec<u8>,
+    // Contact slots, the slot index doubles as the HID contact identifier.
+    slots: [Option<(u64, PhysicalPosition<f64>)>; hid::TOUCH_MAX_CONTACTS],
+    // With the loupe enabled, a lone touch is held back while it's dragged around and only sent to
+    // the host as a tap once it's lifted.
+    loupe: bool,
+    pending: Option<(u64, PhysicalPosition<f64>)>,
+}
+
+impl Touchscreen {
+    pub fn new(loupe: bool) -> Self {
+        Self {
+            report: hid::TouchscreenReport::default(),
+            report_buf: vec![0; hid::TouchscreenReport::LEN],
+            slots: [None; hid::TOUCH_MAX_CONTACTS],
+            loupe,
+            pending: None,
+        }
+    }
+
+    /// Where the magnifier loupe should be shown, if a touch is being positioned.
+    pub fn loupe_position(&self) -> Option<PhysicalPosition<f64>> {
+        self.pending.map(|(_, pos)| pos)
+    }
+
+    pub fn handle_touch(&mut self, touch: Touch, framebuffer_size: PhysicalSize<u32>, gadget: &GadgetProcess) {
+        if let Some((id, pos)) = self.pending {
+            if id == touch.id {
+                match touch.phase {
+                    TouchPhase::Started | TouchPhase::Moved => self.pending = Some((id, touch.location)),
+                    TouchPhase::Ended => {
+                        // Commit the tap where the finger was lifted.
+                        self.pending = None;
+                        self.forward(Touch { phase: TouchPhase::Started, ..touch }, framebuffer_size, gadget);
+                        self.forward(touch, framebuffer_size, gadget);
+                    }
+                    TouchPhase::Cancelled => self.pending = None,
+                }
+                return;
+            }
+            // A second finger means a multi-touch gesture rather than precise targeting, so let
+            // the held back touch through where it is now.
+            self.pending = None;
+            self.forward(
+                Touch { phase: TouchPhase::Started, id, location: pos, ..touch },
+                framebuffer_size,
+                gadget,
+            );
+        } else if self.loupe
+            && touch.phase == TouchPhase::Started
+            && self.slots.iter().all(Option::is_none)
+        {
+            self.pending = Some((touch.id, touch.location));
+            return;
+        }
+
+        self.forward(touch, framebuffer_size, gadget);
+    }
+
+    /// Updates the contact slots for a touch and sends them to the host.
+    fn forward(&mut self, touch: Touch, framebuffer_size: PhysicalSize<u32>, gadget: &GadgetProcess) {
+        let slot = self.slots.iter().position(|slot| matches!(slot, Some((id, _)) if *id == touch.id));
+        let (slot, lifted) = match (touch.phase, slot) {
+            (TouchPhase::Started, None) => match self.slots.iter().position(Option::is_none) {
+                Some(slot) => (slot, false),
+                // Out of contact slots, the host wouldn't accept any more anyway.
+                None => return,
+            },
+            (TouchPhase::Started | TouchPhase::Moved, Some(slot)) => (slot, false),
+            (TouchPhase::Ended | TouchPhase::Cancelled, Some(slot)) => (slot, true),
+            _ => return,
+        };
+        self.slots[slot] = Some((touch.id, touch.location));
+
+        self.send_report(lifted.then_some(slot), framebuffer_size, gadget);
+
+        if lifted {
+            self.slots[slot] = None;
+        }
+    }
+
+    /// Sends all active contacts. A lifted contact is reported once more with the tip switch
+    /// released so the host sees it go up.
+    fn send_report(&mut self, lifted: Option<usize>, framebuffer_size: PhysicalSize<u32>, gadget: &GadgetProcess) {
+        let mut count = 0;
+        for (slot, (contact, state)) in self.report.contacts.iter_mut().zip(&self.slots).enumerate() {
+            *contact = match state {
+                Some((_, pos)) => {
+                    count += 1;
+                    let (x, y) = to_logical(*pos, framebuffer_size);
+                    hid::TouchContact {
+                        tip: lifted != Some(slot),
+                        id: slot as u8,
+                        x,
+                        y,
+                    }
+                }
+                None => hid::TouchContact::default(),
+            };
+        }
+        self.report.count = count;
+
+        ssmarshal::serialize(self.report_buf.as_mut_slice(), &self.report)
+            .expect("report serialization");
+        gadget.send(IpcCommand::TouchReport(self.report_buf.clone())).unwrap();
+    }
+}
+
+/// Maps a framebuffer position onto the 0..=[`hid::TOUCH_LOGICAL_MAX`] range used by the absolute
+/// digitizer reports.
+pub fn to_logical(pos: PhysicalPosition<f64>, framebuffer_size: PhysicalSize<u32>) -> (u16, u16) {
+    let scale = |pos: f64, size: u32| {
+        ((pos / size.max(1) as f64).clamp(0.0, 1.0) * hid::TOUCH_LOGICAL_MAX as f64) as u16
+    };
+    (scale(pos.x, framebuffer_size.width), scale(pos.y, framebuffer_size.height))
+}